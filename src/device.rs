@@ -0,0 +1,82 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// A link that carries raw IPv4 datagrams, e.g. a TUN device.
+pub trait NetDevice: Send {
+    /// Receives one datagram into `buf`, waiting at most `timeout` for it.
+    /// Returns `Ok(None)` if nothing arrived in time.
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>>;
+    /// Sends one datagram.
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+    /// The largest datagram the link carries, which bounds the MSS.
+    fn mtu(&self) -> usize {
+        1500
+    }
+}
+
+impl NetDevice for tun_tap::Iface {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let mut pfd = [libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let timeout_ms = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+        let n = unsafe { libc::poll(pfd.as_mut_ptr(), pfd.len() as libc::nfds_t, timeout_ms) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                // a signal cut the wait short, which is as if nothing arrived
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        if n == 0 {
+            return Ok(None);
+        }
+        tun_tap::Iface::recv(self, buf).map(Some)
+    }
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, buf)
+    }
+}
+
+/// Creates an in-memory point-to-point link: whatever is sent on one end is
+/// received on the other. Running an `Interface` on each end lets two stacks
+/// talk to each other without a kernel device.
+pub fn pipe() -> (Pipe, Pipe) {
+    let (atx, brx) = mpsc::channel();
+    let (btx, arx) = mpsc::channel();
+    (Pipe { tx: atx, rx: arx }, Pipe { tx: btx, rx: brx })
+}
+
+/// One end of a link made by [`pipe`].
+pub struct Pipe {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl NetDevice for Pipe {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(packet) => {
+                let n = std::cmp::min(buf.len(), packet.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok(Some(n))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // nobody on the other end anymore; behave like a silent link
+                std::thread::sleep(timeout);
+                Ok(None)
+            }
+        }
+    }
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // like on a real link, a packet nobody receives is just lost
+        let _ = self.tx.send(buf.to_vec());
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{Clock, NetDevice, SystemClock};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// What an [`Impaired`] device does to the datagrams sent through it.
+/// Probabilities are per datagram and range from 0.0 (never) to 1.0 (always).
+#[derive(Clone, Debug, Default)]
+pub struct Impairments {
+    // seed of the random decisions, so that a failing run can be replayed
+    pub seed: u64,
+    // probability that a datagram is lost
+    pub loss: f64,
+    // fixed delay of every datagram
+    pub latency: Duration,
+    // upper bound of a uniformly random delay on top of `latency`
+    pub jitter: Duration,
+    // probability that a datagram is held back by `reorder_delay`, letting
+    // the ones sent after it overtake it
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    // probability that a datagram is delivered twice
+    pub duplicate: f64,
+    // probability that a single bit of a datagram is flipped
+    pub corrupt: f64,
+    // link capacity in bytes per second, at least 1, or None for no limit;
+    // datagrams queue up behind each other
+    pub bandwidth: Option<u64>,
+}
+
+/// Wraps a device and impairs the datagrams sent through it, e.g. one end of
+/// a [`pipe`](crate::pipe). Wrap both ends to impair both directions.
+pub struct Impaired<D> {
+    inner: D,
+    impairments: Impairments,
+    rng: Rng,
+    // datagrams waiting to be sent, by due time and then in sending order
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    nqueued: u64,
+    // when the link has finished sending what is already queued
+    busy_until: Instant,
+    clock: Box<dyn Clock>,
+}
+
+impl<D: NetDevice> Impaired<D> {
+    /// Impairs `inner` as `impairments` says. Panics if the bandwidth is 0.
+    pub fn new(inner: D, impairments: Impairments) -> Self {
+        Self::with_clock(inner, impairments, SystemClock)
+    }
+    /// Like `new`, but delays datagrams by the time of `clock`, e.g. the
+    /// [`VirtualClock`](crate::VirtualClock) of simulated interfaces.
+    ///
+    /// Panics if the bandwidth is 0, which would never send anything.
+    pub fn with_clock(inner: D, impairments: Impairments, clock: impl Clock + 'static) -> Self {
+        assert_ne!(
+            impairments.bandwidth,
+            Some(0),
+            "a link needs some bandwidth"
+        );
+        Impaired {
+            inner,
+            rng: Rng::new(impairments.seed),
+            impairments,
+            queue: BinaryHeap::new(),
+            nqueued: 0,
+            busy_until: clock.now(),
+            clock: Box::new(clock),
+        }
+    }
+
+    /// Passes on the datagrams that are due.
+    fn flush(&mut self, now: Instant) -> io::Result<()> {
+        while let Some(Reverse((due, _, _))) = self.queue.peek() {
+            if *due > now {
+                break;
+            }
+            let Reverse((_, _, packet)) = self.queue.pop().unwrap();
+            self.inner.send(&packet)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: NetDevice> NetDevice for Impaired<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        let now = self.clock.now();
+        self.flush(now)?;
+        // wake up in time for the next datagram that is due
+        let timeout = match self.queue.peek() {
+            Some(Reverse((due, _, _))) => {
+                std::cmp::min(timeout, due.saturating_duration_since(now))
+            }
+            None => timeout,
+        };
+        let n = self.inner.recv(buf, timeout)?;
+        self.flush(self.clock.now())?;
+        Ok(n)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = self.clock.now();
+        let imp = &self.impairments;
+        if self.rng.chance(imp.loss) {
+            return Ok(buf.len());
+        }
+
+        let mut packet = buf.to_vec();
+        if !packet.is_empty() && self.rng.chance(imp.corrupt) {
+            let bit = self.rng.below(packet.len() as u64 * 8) as usize;
+            packet[bit / 8] ^= 1 << (bit % 8);
+        }
+
+        let mut due = now;
+        if let Some(bandwidth) = imp.bandwidth {
+            let start = std::cmp::max(now, self.busy_until);
+            self.busy_until =
+                start + Duration::from_secs_f64(packet.len() as f64 / bandwidth as f64);
+            due = self.busy_until;
+        }
+        due += imp.latency + imp.jitter.mul_f64(self.rng.unit());
+        if self.rng.chance(imp.reorder) {
+            due += imp.reorder_delay;
+        }
+
+        if self.rng.chance(imp.duplicate) {
+            self.queue
+                .push(Reverse((due, self.nqueued, packet.clone())));
+            self.nqueued += 1;
+        }
+        self.queue.push(Reverse((due, self.nqueued, packet)));
+        self.nqueued += 1;
+
+        self.flush(now)?;
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+}
+
+/// splitmix64; small, seedable and good enough to pick impairments.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    /// Uniform in [0, 1).
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn chance(&mut self, p: f64) -> bool {
+        self.unit() < p
+    }
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// Picks the initial sequence numbers of new connections.
+///
+/// Closures of the same signature implement it too, e.g. `|_, _, _| 0` to
+/// make a test deterministic.
+pub trait IsnGenerator: Send {
+    fn isn(&mut self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32;
+}
+
+impl<F> IsnGenerator for F
+where
+    F: FnMut(SocketAddrV4, SocketAddrV4, Instant) -> u32 + Send,
+{
+    fn isn(&mut self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32 {
+        self(local, remote, now)
+    }
+}
+
+// M ticks every 4 microseconds
+const TICK: Duration = Duration::from_micros(4);
+
+/// The generator of RFC 6528: ISN = M + F(localip, localport, remoteip,
+/// remoteport, secretkey), with M a timer and F a keyed hash. The secret is
+/// random, so an off-path attacker can't guess the ISN of a connection, while
+/// the timer keeps consecutive connections of the same quad apart.
+pub struct Rfc6528 {
+    // SipHash with a random key
+    secret: RandomState,
+    epoch: Option<Instant>,
+}
+
+impl Default for Rfc6528 {
+    fn default() -> Self {
+        Rfc6528 {
+            secret: RandomState::new(),
+            epoch: None,
+        }
+    }
+}
+
+impl Rfc6528 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IsnGenerator for Rfc6528 {
+    fn isn(&mut self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32 {
+        let epoch = *self.epoch.get_or_insert(now);
+        let m = (now.saturating_duration_since(epoch).as_nanos() / TICK.as_nanos()) as u32;
+        let f = self.secret.hash_one((local, remote)) as u32;
+        m.wrapping_add(f)
+    }
+}
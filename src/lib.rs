@@ -1,140 +1,446 @@
+mod clock;
+mod device;
+mod impair;
+mod isn;
 mod tcp;
 
-use std::collections::{HashMap, VecDeque};
+pub use clock::{Clock, SystemClock, VirtualClock};
+pub use device::{NetDevice, Pipe, pipe};
+pub use impair::{Impaired, Impairments};
+pub use isn::{IsnGenerator, Rfc6528};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io;
 use std::io::*;
-use std::sync::{Arc, Condvar, Mutex};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread;
-
-const SENDQUE_SIZE: usize = 1024;
+use std::time::{Duration, Instant};
+
+// room for a few dozen full-sized segments each way
+const DEFAULT_BUFFER_SIZES: tcp::BufferSizes = tcp::BufferSizes {
+    send: 64 * 1024,
+    recv: 64 * 1024,
+};
+// how long packet_loop waits for a packet before it services the connections
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+// address of the stack on the tun network set up by run.sh
+const DEFAULT_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+// dynamic port range suggested by RFC6335
+const EPHEMERAL_PORT_START: u16 = 49152;
+const EPHEMERAL_PORT_COUNT: u16 = 16384;
 
 #[derive(Default)]
 struct Condition {
     cond_pending: Condvar,
     cond_recv: Condvar,
+    cond_estab: Condvar,
+    cond_send: Condvar,
     manager: Mutex<ConnectionManager>,
 }
 
 type InterfaceHandle = Arc<Condition>;
 
+impl Condition {
+    /// Waits on `cond` like `Condvar::wait`, unless nobody else is left to
+    /// notify it, because the interface is driven by `Interface::poll` or
+    /// the packet processing thread has failed.
+    fn wait<'a>(
+        &self,
+        cond: &Condvar,
+        cm: MutexGuard<'a, ConnectionManager>,
+    ) -> io::Result<MutexGuard<'a, ConnectionManager>> {
+        if let Some(e) = cm.failure() {
+            return Err(e);
+        }
+        if cm.simulated {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the interface has to be polled first",
+            ));
+        }
+        Ok(cond.wait(cm).unwrap())
+    }
+
+    /// Wakes up everyone who waits, whatever for.
+    fn notify_all(&self) {
+        self.cond_pending.notify_all();
+        self.cond_recv.notify_all();
+        self.cond_estab.notify_all();
+        self.cond_send.notify_all();
+    }
+}
+
 pub struct Interface {
-    ih: Option<InterfaceHandle>,        // nic handler
-    jh: Option<thread::JoinHandle<()>>, // packet processing thread
+    ih: Option<InterfaceHandle>,                    // nic handler
+    jh: Option<thread::JoinHandle<io::Result<()>>>, // packet processing thread
+    addr: Ipv4Addr,                                 // our address on the network
+    clock: Arc<dyn Clock>,                          // source of time for the timers
+    nic: Option<Box<dyn NetDevice>>,                // the device, if driven by poll
 }
 
-#[derive(Default)]
 struct ConnectionManager {
     terminate: bool,
-    connections: HashMap<tcp::Quad, tcp::Connection>,
+    // no packet processing thread; blocking calls fail with WouldBlock
+    simulated: bool,
+    // ordered, so that connections tick in the same order on every run
+    connections: BTreeMap<tcp::Quad, tcp::Connection>,
     pendings: HashMap<u16, VecDeque<tcp::Quad>>,
+    next_port: u16,
+    buffers: tcp::BufferSizes,
+    verify_checksums: bool,
+    isn: Box<dyn IsnGenerator>,
+    stats: Stats,
+    // why the packet processing thread has stopped, if it has failed
+    failed: Option<io::Error>,
+}
+
+/// Counters of an [`Interface`], see [`Interface::stats`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    // packets dropped because of a bad IPv4 header or TCP checksum
+    pub checksum_errors: u64,
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
-    let mut nic = nic;
-    let ih = ih;
-    let mut buf = [0u8; 1504];
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        ConnectionManager {
+            terminate: false,
+            simulated: false,
+            connections: Default::default(),
+            pendings: Default::default(),
+            next_port: 0,
+            buffers: DEFAULT_BUFFER_SIZES,
+            verify_checksums: true,
+            isn: Box::new(Rfc6528::new()),
+            stats: Default::default(),
+            failed: None,
+        }
+    }
+}
+
+impl ConnectionManager {
+    /// The error that blocking calls fail with once the packet processing
+    /// thread has failed, as nothing moves anymore after that.
+    fn failure(&self) -> Option<io::Error> {
+        self.failed
+            .as_ref()
+            .map(|e| io::Error::new(e.kind(), format!("packet processing failed: {}", e)))
+    }
+    /// Picks a local port that is neither bound nor used towards `remote`.
+    fn ephemeral_port(&mut self, local: Ipv4Addr, remote: SocketAddrV4) -> io::Result<u16> {
+        for _ in 0..EPHEMERAL_PORT_COUNT {
+            let port = EPHEMERAL_PORT_START + self.next_port;
+            self.next_port = (self.next_port + 1) % EPHEMERAL_PORT_COUNT;
+
+            let q = tcp::Quad {
+                src: (*remote.ip(), remote.port()),
+                dst: (local, port),
+            };
+            if !self.pendings.contains_key(&port) && !self.connections.contains_key(&q) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no ephemeral port available",
+        ))
+    }
+}
+
+fn packet_loop(
+    mut nic: Box<dyn NetDevice>,
+    ih: InterfaceHandle,
+    clock: Arc<dyn Clock>,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; nic.mtu()];
     loop {
-        // TODO: block point: to terminate, we need to set timer
-        let eth_nbytes = nic.recv(&mut buf[..])?;
-        // let _eth_flag = u16::from_be_bytes([buf[0], buf[1]]);
-        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-
-        // // if the packet is not ipv4, then drop
-        // if eth_proto != 0x0800 {
-        //     continue;
-        // }
-
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..eth_nbytes]) {
-            Ok(ip_hdr) => {
-                let src_ip = ip_hdr.source_addr();
-                let dst_ip = ip_hdr.destination_addr();
-                if ip_hdr.protocol() != etherparse::IpNumber::TCP {
-                    eprintln!(
-                        "not a tcp packet, so drop it (protocol={})",
-                        ip_hdr.protocol().0
-                    );
-                    continue;
-                }
+        if ih.manager.lock().unwrap().terminate {
+            return Ok(());
+        }
+        if let Err(e) = step(nic.as_mut(), &ih, clock.as_ref(), &mut buf, TICK_INTERVAL) {
+            // nobody would wake up those who wait anymore, so tell them
+            ih.manager.lock().unwrap().failed = Some(io::Error::new(e.kind(), e.to_string()));
+            ih.notify_all();
+            return Err(e);
+        }
+    }
+}
+
+/// Services the connections once and then handles at most one packet,
+/// waiting at most `timeout` for it. Returns whether a packet arrived.
+fn step(
+    nic: &mut dyn NetDevice,
+    ih: &Condition,
+    clock: &dyn Clock,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> io::Result<bool> {
+    {
+        let mut cm = ih.manager.lock().unwrap();
+        let now = clock.now();
+        // give every connection a chance to send what has been queued
+        let mut failed = false;
+        for c in cm.connections.values_mut() {
+            let ok = c.error().is_none();
+            if let Err(e) = c.on_tick(nic, now) {
+                // that is the end of this connection, not of the others
+                c.fail(e);
+            }
+            failed |= ok && c.error().is_some();
+        }
+        cm.connections.retain(|_, c| !c.is_done());
+
+        if failed {
+            // those that timed out before they were accepted go, as if they
+            // never came
+            let ConnectionManager {
+                connections,
+                pendings,
+                ..
+            } = &mut *cm;
+            for pending in pendings.values_mut() {
+                pending.retain(|q| {
+                    let gone = connections.get(q).is_none_or(|c| c.error().is_some());
+                    if gone {
+                        connections.remove(q);
+                    }
+                    !gone
+                });
+            }
+            drop(cm);
+            // and whoever waits on the others gets to see the error
+            ih.notify_all();
+        }
+    }
+
+    let Some(eth_nbytes) = nic.recv(buf, timeout)? else {
+        return Ok(false);
+    };
+    handle_packet(nic, ih, clock.now(), &buf[..eth_nbytes])?;
+    Ok(true)
+}
+
+fn handle_packet(
+    nic: &mut dyn NetDevice,
+    ih: &Condition,
+    now: Instant,
+    packet: &[u8],
+) -> io::Result<()> {
+    // let _eth_flag = u16::from_be_bytes([buf[0], buf[1]]);
+    // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
+
+    // // if the packet is not ipv4, then drop
+    // if eth_proto != 0x0800 {
+    //     return Ok(());
+    // }
+
+    match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+        Ok(ip_hdr) => {
+            let src_ip = ip_hdr.source_addr();
+            let dst_ip = ip_hdr.destination_addr();
+            // the total length has to cover the header and fit what arrived;
+            // anything beyond it is link layer padding
+            let total_len = usize::from(ip_hdr.total_len());
+            if total_len < ip_hdr.slice().len() || total_len > packet.len() {
+                eprintln!(
+                    "bad total length {} of a {} byte packet, so drop it",
+                    total_len,
+                    packet.len()
+                );
+                return Ok(());
+            }
+            let packet = &packet[..total_len];
+            if ip_hdr.protocol() != etherparse::IpNumber::TCP {
                 eprintln!(
-                    "{} => {} {} plen={:?}",
-                    src_ip,
-                    dst_ip,
-                    ip_hdr.protocol().0,
-                    ip_hdr.payload_len().unwrap()
+                    "not a tcp packet, so drop it (protocol={})",
+                    ip_hdr.protocol().0
                 );
+                return Ok(());
+            }
 
-                match etherparse::TcpHeaderSlice::from_slice(&buf[ip_hdr.slice().len()..eth_nbytes])
-                {
-                    Ok(tcp_hdr) => {
-                        use std::collections::hash_map::Entry;
-                        let idx_payload = ip_hdr.slice().len() + tcp_hdr.slice().len();
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let mut cm = &mut *cmg;
-                        let q = tcp::Quad {
-                            src: (src_ip, tcp_hdr.source_port()),
-                            dst: (dst_ip, tcp_hdr.destination_port()),
-                        };
-                        match cm.connections.entry(q) {
-                            Entry::Occupied(mut c) => {
-                                let a = c.get_mut().on_packet(
-                                    &mut nic,
-                                    ip_hdr,
-                                    tcp_hdr,
-                                    &buf[idx_payload..eth_nbytes],
-                                )?;
-
-                                drop(cmg);
-
-                                if a.is_readable() {
-                                    ih.cond_recv.notify_all()
-                                }
+            match etherparse::TcpHeaderSlice::from_slice(&packet[ip_hdr.slice().len()..]) {
+                Ok(tcp_hdr) => {
+                    use std::collections::btree_map::Entry;
+                    let idx_payload = ip_hdr.slice().len() + tcp_hdr.slice().len();
+                    let mut cmg = ih.manager.lock().unwrap();
+                    let cm = &mut *cmg;
+                    if cm.verify_checksums
+                        && !checksums_ok(&ip_hdr, &tcp_hdr, &packet[idx_payload..])
+                    {
+                        eprintln!("bad checksum, so drop it");
+                        cm.stats.checksum_errors += 1;
+                        return Ok(());
+                    }
+                    eprintln!(
+                        "{} => {} {} plen={}",
+                        src_ip,
+                        dst_ip,
+                        ip_hdr.protocol().0,
+                        total_len - ip_hdr.slice().len()
+                    );
+                    let q = tcp::Quad {
+                        src: (src_ip, tcp_hdr.source_port()),
+                        dst: (dst_ip, tcp_hdr.destination_port()),
+                    };
+                    match cm.connections.entry(q) {
+                        Entry::Occupied(mut c) => {
+                            let a = c
+                                .get_mut()
+                                .on_packet(nic, now, ip_hdr, tcp_hdr, &packet[idx_payload..])
+                                .unwrap_or_else(|e| {
+                                    // that is the end of this connection, not of the others
+                                    c.get_mut().fail(e);
+                                    c.get().availability()
+                                });
+                            if c.get().error().is_some()
+                                && let Some(pending) = cm.pendings.get_mut(&q.dst.1)
+                                && let Some(i) = pending.iter().position(|p| *p == q)
+                            {
+                                // reset before it was accepted; as if it never came
+                                pending.remove(i);
+                                c.remove();
                             }
-                            Entry::Vacant(e) => {
-                                if let Some(pending) =
-                                    cm.pendings.get_mut(&tcp_hdr.destination_port())
-                                {
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut nic,
-                                        ip_hdr,
-                                        tcp_hdr,
-                                        &buf[idx_payload..eth_nbytes],
-                                    )? {
+
+                            drop(cmg);
+
+                            if a.is_readable() {
+                                ih.cond_recv.notify_all()
+                            }
+                            if a.is_writable() {
+                                ih.cond_estab.notify_all();
+                                ih.cond_send.notify_all()
+                            }
+                        }
+                        Entry::Vacant(e) => {
+                            let data = &packet[idx_payload..];
+                            // a listener only takes a plain SYN (RFC9293 S3.10.7.2)
+                            let connecting = tcp_hdr.syn() && !tcp_hdr.ack() && !tcp_hdr.rst();
+                            if let Some(pending) = cm.pendings.get_mut(&tcp_hdr.destination_port())
+                                && connecting
+                            {
+                                let iss = cm.isn.isn(
+                                    SocketAddrV4::new(dst_ip, q.dst.1),
+                                    SocketAddrV4::new(src_ip, q.src.1),
+                                    now,
+                                );
+                                match tcp::Connection::accept(
+                                    nic, now, iss, cm.buffers, ip_hdr, tcp_hdr, data,
+                                ) {
+                                    Ok(Some(c)) => {
                                         e.insert(c);
                                         pending.push_back(q);
                                         drop(cmg);
                                         ih.cond_pending.notify_all()
                                     }
+                                    Ok(None) => {}
+                                    // as if the SYN was lost; the peer will retry
+                                    Err(e) => eprintln!("failed to answer a SYN: {}", e),
+                                }
+                            } else {
+                                if let Err(e) = tcp::send_reset(nic, dst_ip, src_ip, &tcp_hdr, data)
+                                {
+                                    eprintln!("failed to send a RST: {}", e);
                                 }
                             }
                         }
                     }
-                    Err(e) => {
-                        eprintln!("weird packet: {}", e);
-                    }
                 }
-            }
-            Err(_) => {
-                // eprintln!("unknown packet: {}", e);
+                Err(e) => {
+                    eprintln!("weird packet: {}", e);
+                }
             }
         }
+        Err(_) => {
+            // eprintln!("unknown packet: {}", e);
+        }
     }
+    Ok(())
+}
+
+fn checksums_ok(
+    ip_hdr: &etherparse::Ipv4HeaderSlice,
+    tcp_hdr: &etherparse::TcpHeaderSlice,
+    payload: &[u8],
+) -> bool {
+    ip_hdr.header_checksum() == ip_hdr.to_header().calc_header_checksum()
+        && tcp_hdr
+            .calc_checksum_ipv4(ip_hdr, payload)
+            .is_ok_and(|sum| sum == tcp_hdr.checksum())
 }
 
 impl Interface {
     pub fn new() -> io::Result<Self> {
+        Self::with_addr(DEFAULT_ADDR)
+    }
+    /// Like `new`, but with `addr` as the source address of outgoing connections.
+    pub fn with_addr(addr: Ipv4Addr) -> io::Result<Self> {
         let nic = tun_tap::Iface::without_packet_info("tun", tun_tap::Mode::Tun)?;
+        Ok(Self::with_device(nic, addr))
+    }
+    /// Runs the stack on top of `nic` instead of the "tun" device, with `addr`
+    /// as our address on that link.
+    pub fn with_device(nic: impl NetDevice + 'static, addr: Ipv4Addr) -> Self {
+        let nic: Box<dyn NetDevice> = Box::new(nic);
         let ih: InterfaceHandle = Arc::default();
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
 
         let jh = {
             let ih = ih.clone();
-            thread::spawn(move || {
-                packet_loop(nic, ih);
-            })
+            let clock = clock.clone();
+            thread::spawn(move || packet_loop(nic, ih, clock))
         };
-        Ok(Interface {
+        Interface {
             ih: Some(ih),
             jh: Some(jh),
-        })
+            addr,
+            clock,
+            nic: None,
+        }
+    }
+    /// Runs the stack on top of `nic` without a packet processing thread and
+    /// with the time taken from `clock`, e.g. a [`VirtualClock`]. Nothing
+    /// happens until [`poll`](Self::poll) is called, and the calls that would
+    /// block fail with `WouldBlock` instead, so that a test can drive one or
+    /// more stacks step by step and deterministically.
+    pub fn simulated(
+        nic: impl NetDevice + 'static,
+        addr: Ipv4Addr,
+        clock: impl Clock + 'static,
+    ) -> Self {
+        let ih: InterfaceHandle = Arc::default();
+        ih.manager.lock().unwrap().simulated = true;
+        Interface {
+            ih: Some(ih),
+            jh: None,
+            addr,
+            clock: Arc::new(clock),
+            nic: Some(Box::new(nic)),
+        }
+    }
+    /// Handles the packets that have arrived and runs the timers that are
+    /// due, without waiting. Returns the number of packets handled.
+    ///
+    /// Only interfaces made by [`simulated`](Self::simulated) need this; on
+    /// the others the packet processing thread does it, and `poll` returns 0,
+    /// so that code can drive either kind.
+    pub fn poll(&mut self) -> io::Result<usize> {
+        let Some(nic) = self.nic.as_mut() else {
+            return Ok(0);
+        };
+        let ih = self.ih.as_ref().unwrap();
+        let mut buf = vec![0u8; nic.mtu()];
+        let mut npackets = 0;
+        while step(
+            nic.as_mut(),
+            ih,
+            self.clock.as_ref(),
+            &mut buf,
+            Duration::ZERO,
+        )? {
+            npackets += 1;
+        }
+        Ok(npackets)
     }
     pub fn bind(&mut self, port: u16) -> Result<TcpListener> {
         use std::collections::hash_map::Entry;
@@ -154,6 +460,85 @@ impl Interface {
             h: self.ih.as_mut().unwrap().clone(),
         })
     }
+    /// Sets the receive buffer size, and with it the largest window advertised,
+    /// of connections opened or accepted from now on.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.ih
+            .as_mut()
+            .unwrap()
+            .manager
+            .lock()
+            .unwrap()
+            .buffers
+            .recv = size;
+    }
+    /// Sets the send buffer size, i.e. how much the application may queue
+    /// before writes block, of connections opened or accepted from now on.
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.ih
+            .as_mut()
+            .unwrap()
+            .manager
+            .lock()
+            .unwrap()
+            .buffers
+            .send = size;
+    }
+    /// Turns checking the IPv4 header and TCP checksums of incoming packets
+    /// on or off. It is on by default; turn it off if the device has already
+    /// checked them, e.g. with checksum offloading.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.ih
+            .as_mut()
+            .unwrap()
+            .manager
+            .lock()
+            .unwrap()
+            .verify_checksums = verify;
+    }
+    /// Replaces the generator of initial sequence numbers, which by default
+    /// is [`Rfc6528`] with a secret of this interface.
+    pub fn set_isn_generator(&mut self, isn: impl IsnGenerator + 'static) {
+        self.ih.as_mut().unwrap().manager.lock().unwrap().isn = Box::new(isn);
+    }
+    /// Returns the counters of the interface so far.
+    pub fn stats(&self) -> Stats {
+        self.ih.as_ref().unwrap().manager.lock().unwrap().stats
+    }
+    /// Opens a connection to `addr` and blocks until it is established.
+    /// A simulated interface returns right away; the stream becomes usable
+    /// once the handshake has been polled through.
+    pub fn connect(&mut self, addr: SocketAddrV4) -> io::Result<TcpStream> {
+        let ih = self.ih.as_mut().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        let port = cm.ephemeral_port(self.addr, addr)?;
+        let quad = tcp::Quad {
+            src: (*addr.ip(), addr.port()),
+            dst: (self.addr, port),
+        };
+        let now = self.clock.now();
+        let iss = cm.isn.isn(SocketAddrV4::new(self.addr, port), addr, now);
+        let c = tcp::Connection::connect(quad, iss, cm.buffers, now);
+        cm.connections.insert(quad, c);
+
+        while !cm.simulated {
+            let c = cm.connections.get(&quad).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::ConnectionRefused, "connection failed")
+            })?;
+            if let Some(e) = c.error() {
+                cm.connections.remove(&quad);
+                return Err(e);
+            }
+            if c.is_synchronized() {
+                break;
+            }
+            cm = ih.wait(&ih.cond_estab, cm)?;
+        }
+        Ok(TcpStream {
+            quad,
+            h: ih.clone(),
+        })
+    }
 }
 
 impl Drop for Interface {
@@ -161,7 +546,12 @@ impl Drop for Interface {
         self.ih.as_mut().unwrap().manager.lock().unwrap().terminate = true;
 
         drop(self.ih.take());
-        self.jh.take().expect("already dropped").join().unwrap();
+        if let Some(jh) = self.jh.take()
+            && let Err(e) = jh.join().unwrap()
+        {
+            // too late to do anything about it, but don't panic in drop
+            eprintln!("packet loop failed: {}", e);
+        }
     }
 }
 
@@ -173,11 +563,17 @@ pub struct TcpStream {
 impl Drop for TcpStream {
     fn drop(&mut self) {
         let mut cm = self.h.manager.lock().unwrap();
-        // TODO: send FIN on cm.connections[quad]
+        if let Some(c) = cm.connections.get_mut(&self.quad) {
+            // packet_loop sends the FIN and reaps the connection once it is CLOSED
+            c.release();
+        }
     }
 }
 
 impl TcpStream {
+    /// Shuts down the read half, the write half or both, like
+    /// `std::net::TcpStream::shutdown`. A FIN follows the queued data once
+    /// writing is shut down; reads return 0 once reading is.
     pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
         let mut cm = self.h.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
@@ -187,7 +583,13 @@ impl TcpStream {
             )
         })?;
 
-        c.close()
+        c.shutdown(how);
+        drop(cm);
+
+        // blocked readers and writers have to see the new state
+        self.h.cond_recv.notify_all();
+        self.h.cond_send.notify_all();
+        Ok(())
     }
 }
 
@@ -199,6 +601,9 @@ impl Read for TcpStream {
                 io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated")
             })?;
 
+            if let Some(e) = c.error() {
+                return Err(e);
+            }
             if c.is_recv_closed() && c.incoming.is_empty() {
                 return Ok(0);
             }
@@ -216,44 +621,55 @@ impl Read for TcpStream {
                 drop(c.incoming.drain(..nbytes));
                 return Ok(nbytes);
             }
-            cm = self.h.cond_recv.wait(cm).unwrap();
+            cm = self.h.wait(&self.h.cond_recv, cm)?;
         }
     }
 }
 
 impl Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let mut ih = self.h.manager.lock().unwrap();
-        let c = ih.connections.get_mut(&self.quad).ok_or_else(|| {
-            io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated")
-        })?;
-
-        if c.unacked.len() >= SENDQUE_SIZE {
-            // TODO: block
-            return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes in buffer",
-            ));
+        let mut cm = self.h.manager.lock().unwrap();
+        if let Some(e) = cm.failure() {
+            // nothing queued would ever be sent
+            return Err(e);
         }
+        loop {
+            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated")
+            })?;
 
-        let nbytes = std::cmp::min(SENDQUE_SIZE - c.unacked.len(), buf.len());
-        c.unacked.extend(buf[..nbytes].iter());
-        Ok(nbytes)
+            if let Some(e) = c.error() {
+                return Err(e);
+            }
+            if c.is_send_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "stream was closed for writing",
+                ));
+            }
+
+            if c.unacked.len() < c.send_buffer_size {
+                let nbytes = std::cmp::min(c.send_buffer_size - c.unacked.len(), buf.len());
+                c.unacked.extend(buf[..nbytes].iter());
+                return Ok(nbytes);
+            }
+            cm = self.h.wait(&self.h.cond_send, cm)?;
+        }
     }
     fn flush(&mut self) -> Result<()> {
-        let mut ih = self.h.manager.lock().unwrap();
-        let c = ih.connections.get_mut(&self.quad).ok_or_else(|| {
-            io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated")
-        })?;
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated")
+            })?;
 
-        if c.unacked.is_empty() {
-            Ok(())
-        } else {
-            // TODO: block
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes in buffer",
-            ))
+            if let Some(e) = c.error() {
+                return Err(e);
+            }
+            if c.unacked.is_empty() {
+                return Ok(());
+            }
+            cm = self.h.wait(&self.h.cond_send, cm)?;
         }
     }
 }
@@ -272,8 +688,12 @@ impl Drop for TcpListener {
             .remove(&self.port)
             .expect("port closed with active listener");
 
+        // connections that were never accepted have no owner left; packet_loop
+        // resets them and reaps them
         for quad in pendings {
-            unimplemented!()
+            if let Some(c) = cm.connections.get_mut(&quad) {
+                c.reset();
+            }
         }
     }
 }
@@ -294,7 +714,7 @@ impl TcpListener {
                     h: self.h.clone(),
                 });
             }
-            cm = self.h.cond_pending.wait(cm).unwrap();
+            cm = self.h.wait(&self.h.cond_pending, cm)?;
         }
     }
 }
@@ -1,9 +1,42 @@
-use etherparse::TcpHeaderSlice;
+use crate::NetDevice;
+use etherparse::{TcpHeaderSlice, TcpOptionElement};
 use std::collections::VecDeque;
 use std::io;
 use std::io::Cursor;
 use std::io::Write;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Shutdown};
+use std::time::{Duration, Instant};
+
+mod reassembly;
+mod sack;
+use reassembly::Reassembly;
+use sack::Scoreboard;
+
+// size of bare IPv4 and TCP headers, which the MSS excludes
+const HEADERS_LEN: usize = 40;
+// MSS to assume when the peer doesn't announce one (RFC9293 S3.7.1)
+const DEFAULT_MSS: usize = 536;
+// smallest MSS we go along with, as Linux's TCP_MIN_SND_MSS; any less leaves
+// next to no room for data beside the options
+const MIN_MSS: usize = 48;
+// largest window scale shift allowed (RFC7323 S2.3)
+const MAX_WINDOW_SHIFT: u8 = 14;
+// how long TS.Recent stays valid without news from the peer (RFC7323 S5.5)
+const PAWS_IDLE: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+// RTO bounds and clock granularity (RFC6298 S2 and S4)
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+// retransmissions of a segment before giving up on the peer, about four
+// minutes with the backoff, past the R2 of a SYN (RFC9293 S3.8.3)
+const MAX_RETRANSMISSIONS: u32 = 8;
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+// maximum segment lifetime; TIME-WAIT lasts twice as long
+const MSL: Duration = Duration::from_secs(30);
+// how long a connection the application has dropped waits in FIN-WAIT-2 for
+// the peer's FIN, like Linux's tcp_fin_timeout
+const FIN_WAIT_2_TIMEOUT: Duration = Duration::from_secs(60);
 
 const CAP_READ: u8 = 0b00000001;
 const CAP_WRITE: u8 = 0b00000010;
@@ -17,9 +50,22 @@ impl Available {
     pub fn is_readable(&self) -> bool {
         (self.flag & CAP_READ) > 0
     }
+    pub fn is_writable(&self) -> bool {
+        (self.flag & CAP_WRITE) > 0
+    }
 }
 
-#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy)]
+/// How much a connection buffers in either direction.
+#[derive(Clone, Copy, Debug)]
+pub struct BufferSizes {
+    // what the application may queue for sending
+    pub send: usize,
+    // what may arrive before the application reads it, which bounds the
+    // window we advertise
+    pub recv: usize,
+}
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Clone, Copy)]
 pub struct Quad {
     pub src: (Ipv4Addr, u16),
     pub dst: (Ipv4Addr, u16),
@@ -28,25 +74,30 @@ pub struct Quad {
 #[derive(PartialEq)]
 enum State {
     // Listen,
+    SynSent,
     SynRcvd,
     Estab,
     FinWait1,
     FinWait2,
     CloseWait,
     Closing,
+    LastAck,
     TimeWait,
+    Closed,
 }
 
 impl State {
     fn is_synchronized(&self) -> bool {
-        match *self {
+        matches!(
+            *self,
             State::Estab
-            | State::FinWait1
-            | State::FinWait2
-            | State::CloseWait
-            | State::Closing => true,
-            _ => false,
-        }
+                | State::FinWait1
+                | State::FinWait2
+                | State::CloseWait
+                | State::Closing
+                | State::LastAck
+                | State::TimeWait
+        )
     }
 }
 
@@ -67,10 +118,10 @@ struct SendSequenceSpace {
     una: u32,
     // send next
     nxt: u32,
-    // send window
-    wnd: u16,
-    // send urgent pointer
-    up: bool,
+    // send window, already scaled
+    wnd: u32,
+    // how far the peer scales the windows it announces (RFC7323 S2.2)
+    shift: u8,
     // segment sequence number used for last window update
     wl1: u32,
     // segment acknowledgment number used for last window update
@@ -93,46 +144,294 @@ struct SendSequenceSpace {
 struct ReceiveSequenceSpace {
     // receive next
     nxt: u32,
-    // receive window
-    wnd: u16,
-    // receive urgent pointer
-    up: bool,
+    // receive window, as we last announced it
+    wnd: u32,
+    // how far we scale the windows we announce (RFC7323 S2.2)
+    shift: u8,
     // initial receive sequence number
     irs: u32,
 }
 
+/// Retransmission timer (RFC6298)
+struct RetransmissionTimer {
+    // smoothed round-trip time, unknown until the first measurement
+    srtt: Option<Duration>,
+    // round-trip time variation
+    rttvar: Duration,
+    // retransmission timeout, including any backoff
+    rto: Duration,
+    // deadline of the running timer
+    expires: Option<Instant>,
+    // segment being timed for an RTT measurement: its end sequence and send time
+    timed: Option<(u32, Instant)>,
+    // how often SND.UNA has been retransmitted
+    retransmissions: u32,
+}
+
+impl Default for RetransmissionTimer {
+    fn default() -> Self {
+        RetransmissionTimer {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            expires: None,
+            timed: None,
+            retransmissions: 0,
+        }
+    }
+}
+
+impl RetransmissionTimer {
+    /// Called whenever a segment that occupies sequence space goes out.
+    fn on_send(&mut self, end: u32, retransmission: bool, now: Instant) {
+        if retransmission {
+            // Karn's algorithm: an ACK can't tell which copy it is for
+            self.timed = None;
+        } else if self.timed.is_none() {
+            self.timed = Some((end, now));
+        }
+        // (5.1) start the timer if it is not running
+        if self.expires.is_none() {
+            self.expires = Some(now + self.rto);
+        }
+    }
+
+    /// Called when SND.UNA advances to `ack`, with the round-trip time that
+    /// the echoed timestamp tells, if any.
+    fn on_ack(&mut self, ack: u32, all_acked: bool, rtt: Option<Duration>, now: Instant) {
+        if let Some(rtt) = rtt {
+            // the echo tells which copy got acked, so unlike with Karn's
+            // algorithm retransmissions can be timed too (RFC7323 S4.1)
+            self.timed = None;
+            self.measure(rtt);
+        } else if let Some((end, sent)) = self.timed
+            && !wrapping_lt(ack, end)
+        {
+            self.timed = None;
+            self.measure(now - sent);
+        }
+        self.retransmissions = 0;
+        // (5.2) stop the timer when everything is acked, (5.3) restart it otherwise
+        self.expires = if all_acked {
+            None
+        } else {
+            Some(now + self.rto)
+        };
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|t| t <= now)
+    }
+
+    /// Whether the peer has failed to ack SND.UNA for so long that the
+    /// connection should be given up (R2 of RFC9293 S3.8.3).
+    fn is_exhausted(&self) -> bool {
+        self.retransmissions >= MAX_RETRANSMISSIONS
+    }
+
+    /// Doubles the RTO and restarts the timer after a retransmission (5.5, 5.6).
+    fn back_off(&mut self, now: Instant) {
+        self.retransmissions += 1;
+        self.rto = std::cmp::min(self.rto * 2, MAX_RTO);
+        self.expires = Some(now + self.rto);
+    }
+
+    fn measure(&mut self, r: Duration) {
+        match self.srtt {
+            None => {
+                // (2.2) first measurement
+                self.srtt = Some(r);
+                self.rttvar = r / 2;
+            }
+            Some(srtt) => {
+                // (2.3) with alpha=1/8 and beta=1/4
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(r)) / 4;
+                self.srtt = Some((srtt * 7 + r) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap();
+        self.rto =
+            (srtt + std::cmp::max(CLOCK_GRANULARITY, self.rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+}
+
 pub struct Connection {
     state: State,
     send: SendSequenceSpace,
     recv: ReceiveSequenceSpace,
+    timer: RetransmissionTimer,
+    // time of the event being handled
+    now: Instant,
+    // when TIME-WAIT is over
+    time_wait: Option<Instant>,
+    // when a released connection stops waiting in FIN-WAIT-2
+    fin_wait_2: Option<Instant>,
+    // when to probe a zero window
+    persist: Option<Instant>,
     iph: etherparse::Ipv4Header,
     tcph: etherparse::TcpHeader,
 
     pub(crate) incoming: VecDeque<u8>,
+    // capacity of incoming, which bounds the window we advertise
+    recv_buffer_size: usize,
+    // data received ahead of RCV.NXT
+    reassembly: Reassembly,
     pub(crate) unacked: VecDeque<u8>,
+    // capacity of unacked
+    pub(crate) send_buffer_size: usize,
+    // largest payload we send, once the peer's SYN has told us
+    mss: usize,
+    // whether to offer window scaling on our SYN; not if the peer's came without
+    window_scaling: bool,
+    // whether to send timestamps (RFC7323 S3); not if the peer's SYN came without
+    timestamps: bool,
+    // whether to use SACK (RFC2018); not if the peer's SYN came without
+    sack: bool,
+    // what the peer has SACKed of the data in flight
+    scoreboard: Scoreboard,
+    // our timestamps count milliseconds from here
+    created: Instant,
+    // TS.Recent, the peer's timestamp to echo, and when we took it
+    ts_recent: u32,
+    ts_recent_age: Instant,
 
+    // set by the application; a FIN follows the queued data
     pub(crate) closed: bool,
+    // set by the application; received data gets dropped
+    read_closed: bool,
+    // the application has dropped its TcpStream
+    released: bool,
+    // to be aborted with a RST on the next tick
+    reset: bool,
+    // set once the peer has reset the connection, for the application to see
+    error: Option<io::ErrorKind>,
 }
 
 impl Connection {
     pub(crate) fn is_recv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // TODO: other state
-            true
-        } else {
-            false
-        }
+        // the application shut down reading, or the peer's FIN has been received
+        self.read_closed
+            || matches!(
+                self.state,
+                State::CloseWait
+                    | State::Closing
+                    | State::LastAck
+                    | State::TimeWait
+                    | State::Closed
+            )
+    }
+    /// True once the application has dropped the connection and the state
+    /// machine has reached CLOSED, i.e. nobody needs it anymore.
+    pub(crate) fn is_done(&self) -> bool {
+        self.released && self.state == State::Closed
+    }
+    pub(crate) fn is_synchronized(&self) -> bool {
+        self.state.is_synchronized()
+    }
+    /// The error that reads and writes fail with after a reset by the peer.
+    pub(crate) fn error(&self) -> Option<io::Error> {
+        self.error.map(|kind| match kind {
+            io::ErrorKind::ConnectionRefused => io::Error::new(kind, "connection refused"),
+            io::ErrorKind::TimedOut => io::Error::new(kind, "connection timed out"),
+            io::ErrorKind::ConnectionReset => io::Error::new(kind, "connection reset by peer"),
+            _ => kind.into(),
+        })
+    }
+    pub(crate) fn is_send_closed(&self) -> bool {
+        // the application closed the connection, or we already sent our FIN
+        self.closed
+            || !matches!(
+                self.state,
+                State::SynSent | State::SynRcvd | State::Estab | State::CloseWait
+            )
     }
     pub(crate) fn availability(&self) -> Available {
         let mut x = Available::default();
         if self.is_recv_closed() || !self.incoming.is_empty() {
             x.flag |= CAP_READ;
         }
-        // TODO: cap for write
+        if self.is_send_closed() || self.unacked.len() < self.send_buffer_size {
+            x.flag |= CAP_WRITE;
+        }
         x
     }
+    fn new(
+        state: State,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        iss: u32,
+        buffers: BufferSizes,
+        now: Instant,
+    ) -> Self {
+        let recv_buffer_size = buffers.recv;
+        // the smallest shift that lets the window cover the whole buffer
+        let mut shift = 0;
+        while shift < MAX_WINDOW_SHIFT && recv_buffer_size >> shift > u16::MAX as usize {
+            shift += 1;
+        }
+        let wnd = std::cmp::min(recv_buffer_size, u16::MAX as usize) as u16;
+        Connection {
+            state,
+            // the window is filled in once we see the peer's SYN
+            send: SendSequenceSpace {
+                iss,
+                una: iss,
+                nxt: iss,
+                wnd: 0,
+                shift: 0,
+                wl1: 0,
+                wl2: 0,
+            },
+            // filled in once we see the peer's SYN
+            recv: ReceiveSequenceSpace {
+                irs: 0,
+                nxt: 0,
+                wnd: wnd.into(),
+                shift,
+            },
+            tcph: etherparse::TcpHeader::new(local.1, remote.1, iss, wnd),
+            iph: etherparse::Ipv4Header::new(
+                0, // payload length will be set in write()
+                64,
+                etherparse::IpNumber::TCP,
+                local.0.octets(),
+                remote.0.octets(),
+            )
+            .unwrap(),
+            timer: Default::default(),
+            now,
+            time_wait: None,
+            fin_wait_2: None,
+            persist: None,
+            incoming: Default::default(),
+            recv_buffer_size,
+            reassembly: Default::default(),
+            unacked: Default::default(),
+            send_buffer_size: buffers.send,
+            mss: DEFAULT_MSS,
+            window_scaling: true,
+            timestamps: true,
+            sack: true,
+            scoreboard: Default::default(),
+            created: now,
+            ts_recent: 0,
+            ts_recent_age: now,
+            closed: false,
+            read_closed: false,
+            released: false,
+            reset: false,
+            error: None,
+        }
+    }
+    /// Starts an active open for `quad`. The SYN goes out on the next tick.
+    pub fn connect(quad: Quad, iss: u32, buffers: BufferSizes, now: Instant) -> Self {
+        Connection::new(State::SynSent, quad.dst, quad.src, iss, buffers, now)
+    }
     pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
+        nic: &mut dyn NetDevice,
+        now: Instant,
+        iss: u32,
+        buffers: BufferSizes,
         iph: etherparse::Ipv4HeaderSlice<'a>,
         tcph: TcpHeaderSlice<'a>,
         data: &'a [u8],
@@ -153,79 +452,68 @@ impl Connection {
             return Ok(None);
         }
 
-        let iss = 0;
-        let wnd = 1024;
-        let mut c = Connection {
-            state: State::SynRcvd,
-            send: SendSequenceSpace {
-                iss,
-                una: iss,
-                nxt: iss,
-                wnd,
-                up: false,
-                wl1: 0,
-                wl2: 0,
-            },
-            recv: ReceiveSequenceSpace {
-                irs: tcph.sequence_number(),
-                nxt: tcph.sequence_number().wrapping_add(1),
-                wnd: tcph.window_size(),
-                up: false,
-            },
-            tcph: etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd),
-            iph: etherparse::Ipv4Header::new(
-                0, // payload length will be set in write()
-                64,
-                etherparse::IpNumber::TCP,
-                [
-                    iph.destination()[0],
-                    iph.destination()[1],
-                    iph.destination()[2],
-                    iph.destination()[3],
-                ],
-                [
-                    iph.source()[0],
-                    iph.source()[1],
-                    iph.source()[2],
-                    iph.source()[3],
-                ],
-            )
-            .unwrap(),
-            incoming: Default::default(),
-            unacked: Default::default(),
-            closed: false,
-        };
+        let mut c = Connection::new(
+            State::SynRcvd,
+            (iph.destination_addr(), tcph.destination_port()),
+            (iph.source_addr(), tcph.source_port()),
+            iss,
+            buffers,
+            now,
+        );
+        c.recv.irs = tcph.sequence_number();
+        c.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        // the window of a SYN is never scaled
+        c.send.wnd = tcph.window_size().into();
+        c.negotiate(nic, &tcph);
         c.tcph.syn = true;
         c.tcph.ack = true;
-        c.send_ack(nic, &[]);
+        c.send_ack(nic, &[])?;
 
         Ok(Some(c))
     }
     pub fn write(
         &mut self,
-        nic: &mut tun_tap::Iface,
+        nic: &mut dyn NetDevice,
         seq: u32,
         payload: &[u8],
     ) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
+        let mut buf = vec![0u8; nic.mtu()];
         let buf_len = buf.len();
         let mut cursor = Cursor::new(&mut buf[..]);
 
         self.tcph.sequence_number = seq;
         self.tcph.acknowledgment_number = self.recv.nxt;
+        self.recv.wnd = self.recv_window();
+        if self.tcph.syn {
+            // the window of a SYN is never scaled
+            self.recv.wnd = std::cmp::min(self.recv.wnd, u16::MAX.into());
+            self.tcph.window_size = self.recv.wnd as u16;
+            let options = self.syn_options(nic);
+            self.tcph.set_options(&options).unwrap();
+        } else {
+            self.tcph.window_size = (self.recv.wnd >> self.recv.shift) as u16;
+            let options = self.segment_options();
+            self.tcph.set_options(&options).unwrap();
+        }
 
-        let size = std::cmp::min(
-            buf_len,
-            self.tcph.header_len() as usize + self.iph.header_len() as usize + payload.len(),
-        );
+        let size = self.tcph.header_len() + self.iph.header_len() + payload.len();
+        if size > buf_len {
+            // callers size the payload with segment_size()
+            self.tcph.syn = false;
+            self.tcph.fin = false;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "segment doesn't fit the MTU",
+            ));
+        }
         // ip part
         self.iph
-            .set_payload_len(size - self.iph.header_len() as usize)
+            .set_payload_len(size - self.iph.header_len())
             .unwrap();
         self.iph.write(&mut cursor)?;
 
         // tcp part
-        self.tcph.checksum = self.tcph.calc_checksum_ipv4(&self.iph, &[]).unwrap();
+        self.tcph.checksum = self.tcph.calc_checksum_ipv4(&self.iph, payload).unwrap();
         self.tcph.write(&mut cursor)?;
 
         // inner state part
@@ -239,83 +527,176 @@ impl Connection {
             next_seq = next_seq.wrapping_add(1);
             self.tcph.fin = false;
         }
-        self.send.nxt = next_seq;
+        if next_seq != seq {
+            // the segment occupies sequence space, so it has to be acked
+            let retransmission = !wrapping_lt(self.send.nxt, next_seq);
+            self.timer.on_send(next_seq, retransmission, self.now);
+            if !retransmission {
+                self.send.nxt = next_seq;
+            }
+        }
 
         // flush the buffer to nic
         let used = cursor.position() as usize;
         let n = nic.send(&buf[..used])?;
         Ok(n)
     }
-    pub fn send_ack(&mut self, nic: &mut tun_tap::Iface, buf: &[u8]) -> io::Result<usize> {
+    pub fn send_ack(&mut self, nic: &mut dyn NetDevice, buf: &[u8]) -> io::Result<usize> {
         self.write(nic, self.send.nxt, buf)
     }
-    pub fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        // 3.  If the connection is in a synchronized state (ESTABLISHED,
-        //     FIN-WAIT-1, FIN-WAIT-2, CLOSE-WAIT, CLOSING, LAST-ACK, TIME-WAIT),
-        //     any unacceptable segment (out of window sequence number or
-        //     unacceptible acknowledgment number) must elicit only an empty
-        //     acknowledgment segment containing the current send-sequence number
-        //     and an acknowledgment indicating the next sequence number expected
-        //     to be received, and the connection remains in the same state.
-        self.tcph.rst = true;
-        // TODO: the ACK field is set to the sum of the sequence number and segment
-        // length of the incoming segment
-        self.tcph.acknowledgment_number = 0;
-        self.iph.set_payload_len(self.tcph.header_len()).unwrap();
-        self.write(nic, 0, &[])?;
-        Ok(())
+    /// Answers `tcph`, a segment that is out of place in the current state,
+    /// with a RST. Unlike `write`, this leaves `self.tcph` alone, so that the
+    /// RST bit doesn't stick to the segments that follow.
+    pub fn send_rst(
+        &self,
+        nic: &mut dyn NetDevice,
+        tcph: &TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<()> {
+        send_reset(
+            nic,
+            self.iph.source.into(),
+            self.iph.destination.into(),
+            tcph,
+            data,
+        )
     }
 
     pub fn on_packet<'a>(
         &mut self,
-        nic: &mut tun_tap::Iface,
+        nic: &mut dyn NetDevice,
+        now: Instant,
         _iph: etherparse::Ipv4HeaderSlice<'a>,
         tcph: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8],
     ) -> io::Result<Available> {
+        self.now = now;
+        if let State::Closed = self.state {
+            // all that is left is the entry the application still holds on
+            // to, so answer as if there were no connection (RFC9293 S3.10.7.1)
+            self.send_rst(nic, &tcph, data)?;
+            return Ok(self.availability());
+        }
+        if let State::SynSent = self.state {
+            return self.on_syn_sent(nic, tcph, data);
+        }
+        let ts = if self.timestamps {
+            timestamps(&tcph)
+        } else {
+            None
+        };
+
+        // PAWS (RFC7323 S5.3): a segment stamped before TS.Recent is an old
+        // duplicate, even if its sequence numbers look fine after a wrap
+        if let Some((tsval, _)) = ts
+            && !tcph.rst()
+            && wrapping_lt(tsval, self.ts_recent)
+            && now - self.ts_recent_age < PAWS_IDLE
+        {
+            eprintln!(
+                "old timestamp: tsval={}, ts.recent={}",
+                tsval, self.ts_recent
+            );
+            self.send_ack(nic, &[])?;
+            return Ok(self.availability());
+        }
+
         // check sequence number
         // RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
         //   or
         // RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
-        let seq = tcph.sequence_number();
+        let mut seq = tcph.sequence_number();
+        let mut syn = tcph.syn();
+        if syn && seq == self.recv.irs && self.state == State::SynRcvd {
+            // the SYN-ACK of a simultaneous open, whose SYN we have already
+            // consumed, so only look at the rest of it. Once synchronized, a
+            // repeated SYN-ACK means the peer missed our ACK; it lies below
+            // RCV.NXT and gets one as an unacceptable segment.
+            seq = seq.wrapping_add(1);
+            syn = false;
+        }
         let mut slen = data.len() as u32;
-        if tcph.syn() {
+        if syn {
             slen += 1;
         }
         if tcph.fin() {
             slen += 1;
         }
-        let toe = self.recv.nxt.wrapping_add(self.recv.wnd.into());
-        let valid_range = if slen == 0 {
-            if self.recv.wnd == 0 {
-                seq != self.recv.nxt
-            } else {
-                is_between_wrapped(self.recv.nxt.wrapping_sub(1), seq, toe)
-            }
+        // reading may have opened the window since we last advertised it,
+        // but its right edge never moves left, so the current one will do
+        let wnd = self.recv_window();
+        let toe = self.recv.nxt.wrapping_add(wnd);
+        let valid_range = if wnd == 0 {
+            // nothing fits, but the ACK of a segment at RCV.NXT still counts;
+            // its data gets dropped in receive()
+            seq == self.recv.nxt
+        } else if slen == 0 {
+            is_between_wrapped(self.recv.nxt.wrapping_sub(1), seq, toe)
         } else {
-            self.recv.wnd != 0
-                && (is_between_wrapped(self.recv.nxt.wrapping_sub(1), seq, toe)
-                    || is_between_wrapped(
-                        self.recv.nxt.wrapping_sub(1),
-                        seq.wrapping_add(slen).wrapping_sub(1),
-                        toe,
-                    ))
+            is_between_wrapped(self.recv.nxt.wrapping_sub(1), seq, toe)
+                || is_between_wrapped(
+                    self.recv.nxt.wrapping_sub(1),
+                    seq.wrapping_add(slen).wrapping_sub(1),
+                    toe,
+                )
         };
 
         if !valid_range {
             eprintln!(
                 "invalid range: seq={}, slen={}, recv.nxt={}, recv.wnd={}, toe={}",
-                seq, slen, self.recv.nxt, self.recv.wnd, toe
+                seq, slen, self.recv.nxt, wnd, toe
             );
+            if !tcph.rst() {
+                // RFC9293 S3.10.7.4: an unacceptable segment elicits an ACK
+                self.send_ack(nic, &[])?;
+            }
+            if tcph.fin() && self.state == State::TimeWait {
+                // the peer missed our ACK of its FIN, so wait another 2MSL
+                self.enter_time_wait();
+            }
             return Ok(self.availability());
         }
 
-        if !tcph.ack() {
-            if tcph.syn() {
-                // got SYN in handshake, then we consume seq
-                assert!(data.is_empty());
-                self.recv.nxt = seq.wrapping_add(1);
+        // remember the timestamp to echo, unless the segment lies past what we
+        // acked last (RFC7323 S4.3), which is in the tcph of our last segment
+        if let Some((tsval, _)) = ts
+            && !wrapping_lt(tsval, self.ts_recent)
+            && !wrapping_lt(self.tcph.acknowledgment_number, tcph.sequence_number())
+        {
+            self.ts_recent = tsval;
+            self.ts_recent_age = now;
+        }
+
+        if tcph.rst() {
+            if seq != self.recv.nxt {
+                // RFC5961 S3.2: it may be a blind attack, so instead of giving
+                // up, send a challenge ACK that a genuine peer answers with a
+                // RST right at RCV.NXT
+                self.send_ack(nic, &[])?;
+                return Ok(self.availability());
             }
+            self.error = match self.state {
+                // an active open is refused; a passive one goes back to LISTEN,
+                // which packet_loop takes care of
+                State::SynRcvd => Some(io::ErrorKind::ConnectionRefused),
+                State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait => {
+                    Some(io::ErrorKind::ConnectionReset)
+                }
+                // we are closing anyway, so there is nobody left to tell
+                _ => None,
+            };
+            self.abort();
+            return Ok(self.availability());
+        }
+
+        if syn {
+            // RFC5961 S4.2: any SYN but the one we already took in may be a
+            // blind attack, so, like an inexact RST, it only gets a challenge ACK
+            self.send_ack(nic, &[])?;
+            return Ok(self.availability());
+        }
+
+        if !tcph.ack() {
             return Ok(self.availability());
         }
 
@@ -324,97 +705,621 @@ impl Connection {
         // SND.UNA < SEG.ACK =< SND.NXT
         let ack = tcph.acknowledgment_number();
         if let State::SynRcvd = self.state {
-            if is_between_wrapped(
-                self.send.una.wrapping_sub(1),
-                ack,
-                self.send.nxt.wrapping_add(1),
-            ) {
+            if is_between_wrapped(self.send.una, ack, self.send.nxt.wrapping_add(1)) {
+                // our SYN is acked
+                self.acknowledge(ack, ts);
                 self.state = State::Estab;
+                self.send.wnd = u32::from(tcph.window_size()) << self.send.shift;
+                self.send.wl1 = seq;
+                self.send.wl2 = ack;
             } else {
-                self.send_rst(nic);
+                // it acks something other than our SYN
+                self.send_rst(nic, &tcph, data)?;
                 return Ok(self.availability());
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait
+        | State::Closing
+        | State::LastAck = self.state
+        {
+            if wrapping_lt(self.send.nxt, ack) {
+                // it acks something we haven't sent yet (RFC9293 S3.10.7.4)
+                self.send_ack(nic, &[])?;
+                return Ok(self.availability());
+            }
+            // SND.UNA =< SEG.ACK =< SND.NXT
+            let current = is_between_wrapped(
+                self.send.una.wrapping_sub(1),
+                ack,
+                self.send.nxt.wrapping_add(1),
+            );
             if is_between_wrapped(self.send.una, ack, self.send.nxt.wrapping_add(1)) {
-                self.send.una = ack;
+                self.acknowledge(ack, ts);
+                // freed queue space shows up as CAP_WRITE in availability()
+            }
+            if self.sack && current {
+                let blocks = sack_blocks(&tcph);
+                self.scoreboard
+                    .update(self.send.una, self.send.nxt, &blocks);
             }
 
-            // TODO: the acked data in queue has to be deleted
-            // TODO: notify
-            // TODO: update window
-        }
-
-        if let State::FinWait1 = self.state {
-            if self.send.una == self.send.iss + 2 {
-                // our fin is acked
-                self.state = State::FinWait2;
+            // take the window from the most recent segment only, so that a
+            // reordered old one can't shrink or reopen it (RFC9293 S3.10.7.4)
+            if current
+                && (wrapping_lt(self.send.wl1, seq)
+                    || (self.send.wl1 == seq && !wrapping_lt(ack, self.send.wl2)))
+            {
+                self.send.wnd = u32::from(tcph.window_size()) << self.send.shift;
+                self.send.wl1 = seq;
+                self.send.wl2 = ack;
+                if self.send.wnd == 0 {
+                    // a peer that answers our zero window probes is still
+                    // there, however long it keeps the window closed
+                    self.timer.retransmissions = 0;
+                }
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            // TODO: only read that we haven't read
-            let mut unread_at = self.recv.nxt.wrapping_sub(seq) as usize;
-            if unread_at > data.len() {
-                // reset pointer
-                unread_at = 0;
+        if self.send.una == self.send.nxt {
+            // our FIN, if we sent one, is acked
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(),
+                State::LastAck => self.state = State::Closed,
+                _ => {}
             }
-            self.incoming.extend(&data[unread_at..]);
-
-            self.recv.nxt = seq.wrapping_add(data.len() as u32);
-
-            self.send_ack(nic, &[])?;
         }
 
-        // check the queue
-        if !data.is_empty() {
-            // TODO: set incoming
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state
+            && !data.is_empty()
+        {
+            self.receive(seq, data);
         }
 
-        if tcph.fin() {
-            match self.state {
+        // a FIN only counts once everything in front of it has arrived
+        if tcph.fin() && self.recv.nxt == seq.wrapping_add(data.len() as u32) {
+            let consumed = match self.state {
                 State::SynRcvd | State::Estab => {
                     self.state = State::CloseWait;
+                    true
                 }
                 State::FinWait1 => {
-                    if tcph.ack() && self.send.una == self.send.iss + 2 {
-                        // our fin is acked
-                        self.state = State::TimeWait;
-                    } else {
-                        self.state = State::Closing;
-                        self.send_ack(nic, &[])?;
-                    }
+                    // we got here with our FIN still unacked
+                    self.state = State::Closing;
+                    true
                 }
                 State::FinWait2 => {
                     // done with the conneciton
-                    self.write(nic, 0, &[])?;
-                    self.state = State::TimeWait;
+                    self.enter_time_wait();
+                    true
                 }
-                _ => {}
+                _ => false,
+            };
+            if consumed {
+                // the FIN takes up one sequence number
+                self.recv.nxt = self.recv.nxt.wrapping_add(1);
+            }
+        }
+
+        if slen > 0 {
+            self.send_ack(nic, &[])?;
+        }
+
+        Ok(self.availability())
+    }
+    /// Free space in the receive buffer, which is the window we can offer,
+    /// as far as the window field can express it.
+    fn recv_window(&self) -> u32 {
+        let free = self.recv_buffer_size.saturating_sub(self.incoming.len());
+        let wnd = std::cmp::min(free, (u16::MAX as usize) << self.recv.shift) as u32;
+        // the low bits get lost in the shift
+        wnd >> self.recv.shift << self.recv.shift
+    }
+    /// Accepts segment data at `seq`. In-order bytes go to `incoming` along with
+    /// whatever they make contiguous in the reassembly queue; bytes beyond
+    /// RCV.NXT are held back in the queue.
+    fn receive(&mut self, seq: u32, data: &[u8]) {
+        // ignore whatever doesn't fit the window
+        let toe = self.recv.nxt.wrapping_add(self.recv_window());
+        let end = seq.wrapping_add(data.len() as u32);
+        let data = if wrapping_lt(toe, end) {
+            &data[..data.len() - end.wrapping_sub(toe) as usize]
+        } else {
+            data
+        };
+
+        if wrapping_lt(self.recv.nxt, seq) {
+            // there is a gap in front of this segment
+            self.reassembly.insert(self.recv.nxt, seq, data);
+            return;
+        }
+
+        // skip the part we already have
+        let unread_at = self.recv.nxt.wrapping_sub(seq) as usize;
+        if unread_at < data.len() {
+            self.incoming.extend(&data[unread_at..]);
+            self.recv.nxt = seq.wrapping_add(data.len() as u32);
+        }
+        self.recv.nxt = self
+            .reassembly
+            .drain_into(self.recv.nxt, &mut self.incoming);
+        if self.read_closed {
+            // still acked, but nobody is going to read it
+            self.incoming.clear();
+        }
+    }
+    /// Gives up on the connection because of `e`, e.g. a segment that could
+    /// not be sent, for the application to see.
+    pub(crate) fn fail(&mut self, e: io::Error) {
+        eprintln!("connection failed: {}", e);
+        self.error = Some(e.kind());
+        self.abort();
+    }
+    /// Drops everything and goes to CLOSED, with no more (re)transmissions.
+    fn abort(&mut self) {
+        self.state = State::Closed;
+        self.incoming.clear();
+        self.unacked.clear();
+        self.reassembly = Default::default();
+        self.scoreboard = Default::default();
+        self.timer = Default::default();
+        self.time_wait = None;
+        self.fin_wait_2 = None;
+        self.persist = None;
+    }
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.time_wait = Some(self.now + 2 * MSL);
+    }
+    /// Handles a segment in SYN-SENT (RFC9293 S3.10.7.3).
+    fn on_syn_sent(
+        &mut self,
+        nic: &mut dyn NetDevice,
+        tcph: etherparse::TcpHeaderSlice<'_>,
+        data: &[u8],
+    ) -> io::Result<Available> {
+        let seq = tcph.sequence_number();
+        let ack = tcph.acknowledgment_number();
+        // ISS < SEG.ACK =< SND.NXT
+        if tcph.ack() && !is_between_wrapped(self.send.iss, ack, self.send.nxt.wrapping_add(1)) {
+            if !tcph.rst() {
+                self.send_rst(nic, &tcph, data)?;
             }
+            return Ok(self.availability());
+        }
+        if tcph.rst() {
+            // without an ACK of our SYN it could be about anything
+            if tcph.ack() {
+                self.error = Some(io::ErrorKind::ConnectionRefused);
+                self.abort();
+            }
+            return Ok(self.availability());
+        }
+        if !tcph.syn() {
+            return Ok(self.availability());
         }
 
+        self.recv.irs = seq;
+        self.recv.nxt = seq.wrapping_add(1);
+        self.negotiate(nic, &tcph);
+        self.tcph.ack = true;
+        if tcph.ack() {
+            // our SYN is acked as well
+            self.acknowledge(ack, timestamps(&tcph).filter(|_| self.timestamps));
+            // the window of a SYN is never scaled
+            self.send.wnd = tcph.window_size().into();
+            self.send.wl1 = seq;
+            self.send.wl2 = ack;
+            self.state = State::Estab;
+            self.send_ack(nic, &[])?;
+        } else {
+            // simultaneous open: both SYNs crossed, answer with a SYN-ACK
+            self.state = State::SynRcvd;
+            self.tcph.syn = true;
+            self.write(nic, self.send.iss, &[])?;
+        }
         Ok(self.availability())
     }
-    pub(crate) fn close(&mut self) -> io::Result<()> {
-        self.closed = true;
+
+    /// The options of our SYN or SYN-ACK.
+    fn syn_options(&self, nic: &dyn NetDevice) -> Vec<TcpOptionElement> {
+        // tell the peer how much fits into our MTU (RFC9293 S3.7.1)
+        let mss = nic.mtu().saturating_sub(HEADERS_LEN).min(u16::MAX as usize) as u16;
+        let mut options = vec![TcpOptionElement::MaximumSegmentSize(mss)];
+        // SACK-permitted takes the place of the NOPs in front of the timestamps
+        if self.timestamps {
+            if self.sack {
+                options.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+            } else {
+                options.push(TcpOptionElement::Noop);
+                options.push(TcpOptionElement::Noop);
+            }
+            options.push(TcpOptionElement::Timestamp(self.ts_val(), self.ts_recent));
+        } else if self.sack {
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+        }
+        if self.window_scaling {
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::WindowScale(self.recv.shift));
+        }
+        options
+    }
+
+    /// The options of a segment other than a SYN: the timestamps, and SACK
+    /// blocks while there is out-of-order data.
+    fn segment_options(&self) -> Vec<TcpOptionElement> {
+        let mut options = Vec::new();
+        if self.timestamps {
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::Timestamp(self.ts_val(), self.ts_recent));
+        }
+        // the 40 bytes of option space fit four blocks, three next to timestamps
+        let max = if self.timestamps { 3 } else { 4 };
+        let blocks = self.reassembly.sack_blocks(max);
+        if self.sack
+            && let Some((&first, rest)) = blocks.split_first()
+        {
+            let mut more = [None; 3];
+            for (m, &block) in more.iter_mut().zip(rest) {
+                *m = Some(block);
+            }
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::SelectiveAcknowledgement(first, more));
+        }
+        options
+    }
+
+    /// The most payload a segment can take next to its options, which the
+    /// MSS doesn't leave room for (RFC6691 S2). Always at least one byte, so
+    /// that data keeps moving however much room the options take.
+    fn segment_size(&self) -> usize {
+        let options = etherparse::TcpOptions::try_from_elements(&self.segment_options()).unwrap();
+        std::cmp::max(self.mss.saturating_sub(options.len()), 1)
+    }
+
+    /// Takes in the options of the peer's SYN.
+    fn negotiate(&mut self, nic: &dyn NetDevice, tcph: &TcpHeaderSlice) {
+        self.mss = effective_mss(nic, tcph);
+        let shift = tcph.options_iterator().find_map(|option| match option {
+            Ok(TcpOptionElement::WindowScale(shift)) => Some(shift),
+            _ => None,
+        });
+        match shift {
+            Some(shift) => self.send.shift = std::cmp::min(shift, MAX_WINDOW_SHIFT),
+            None => {
+                // scaling takes both ends (RFC7323 S2.2)
+                self.window_scaling = false;
+                self.recv.shift = 0;
+            }
+        }
+        match timestamps(tcph) {
+            Some((tsval, _)) => {
+                self.ts_recent = tsval;
+                self.ts_recent_age = self.now;
+            }
+            // like scaling, timestamps take both ends (RFC7323 S3.2)
+            None => self.timestamps = false,
+        }
+        self.sack = tcph.options_iterator().any(|option| {
+            matches!(
+                option,
+                Ok(TcpOptionElement::SelectiveAcknowledgementPermitted)
+            )
+        });
+    }
+
+    /// Our timestamp clock, in milliseconds since the connection was created,
+    /// so that it doesn't give away how long the host has been up (RFC7323 S7.1).
+    fn ts_val(&self) -> u32 {
+        self.now.saturating_duration_since(self.created).as_millis() as u32
+    }
+
+    /// Advances SND.UNA to `ack` and drops the acknowledged bytes from `unacked`.
+    /// `ts` is the timestamps option of the acknowledging segment, if any.
+    fn acknowledge(&mut self, ack: u32, ts: Option<(u32, u32)>) {
+        let mut nacked = ack.wrapping_sub(self.send.una) as usize;
+        if let State::SynSent | State::SynRcvd = self.state {
+            // our SYN takes up one sequence number but has no data in the queue;
+            // SND.UNA alone can't tell, it is back at ISS after 2^32 bytes
+            nacked -= 1;
+        }
+        // our FIN is not in the queue either, hence the min
+        let nacked = std::cmp::min(nacked, self.unacked.len());
+        self.unacked.drain(..nacked);
+        self.send.una = ack;
+        self.scoreboard.update(ack, self.send.nxt, &[]);
+        // the peer echoes the timestamp of the segment that got it to ack,
+        // which can't be one from the future
+        let rtt = ts
+            .map(|(_, ecr)| self.ts_val().wrapping_sub(ecr))
+            .filter(|&ms| ms < 1 << 31)
+            .map(|ms| Duration::from_millis(ms.into()));
+        self.timer
+            .on_ack(ack, self.send.una == self.send.nxt, rtt, self.now);
+    }
+
+    /// Resends the oldest unacknowledged segment, starting at SND.UNA and
+    /// stopping short of any data the peer has SACKed.
+    fn retransmit(&mut self, nic: &mut dyn NetDevice) -> io::Result<()> {
+        if let State::SynSent | State::SynRcvd = self.state {
+            // our SYN is still unacked
+            self.tcph.syn = true;
+            return self.write(nic, self.send.una, &[]).map(|_| ());
+        }
+
+        let nflight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        let mut n = std::cmp::min(
+            self.segment_size(),
+            std::cmp::min(nflight, self.unacked.len()),
+        );
+        let hole = self
+            .scoreboard
+            .hole_end(self.send.una, self.send.una, self.send.nxt);
+        if hole == self.send.una {
+            // the peer SACKed what is at SND.UNA, yet didn't ack it, so it
+            // must have dropped it since (RFC2018 S8): start over without SACKs
+            self.scoreboard.clear();
+        } else {
+            n = std::cmp::min(n, hole.wrapping_sub(self.send.una) as usize);
+        }
+        self.scoreboard
+            .retransmitted(self.send.una, self.send.una.wrapping_add(n as u32));
+        // a FIN was sent after the data if it takes up the last sequence number
+        if n == self.unacked.len() && nflight > self.unacked.len() {
+            self.tcph.fin = true;
+        }
+        let payload: Vec<u8> = self.unacked.range(..n).copied().collect();
+        self.write(nic, self.send.una, &payload)?;
         Ok(())
     }
 
-    pub(crate) fn send_fin(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
+    /// Retransmits on RTO expiry, then sends the data in `unacked` that has
+    /// not been sent yet, as far as the send window allows. `unacked[0]`
+    /// always corresponds to SND.UNA.
+    pub(crate) fn on_tick(&mut self, nic: &mut dyn NetDevice, now: Instant) -> io::Result<()> {
+        self.now = now;
+        if self.reset {
+            // ABORT (RFC9293 S3.10.5): <SEQ=SND.NXT><CTL=RST>
+            self.reset = false;
+            self.tcph.rst = true;
+            let sent = self.write(nic, self.send.nxt, &[]);
+            self.tcph.rst = false;
+            self.abort();
+            return sent.map(|_| ());
+        }
+        if self.time_wait.is_some_and(|t| t <= now) {
+            self.time_wait = None;
+            self.state = State::Closed;
+        }
+        if self.released && self.state == State::FinWait2 {
+            // with nobody left to read, don't wait forever for a peer that
+            // may never send its FIN
+            let deadline = *self.fin_wait_2.get_or_insert(now + FIN_WAIT_2_TIMEOUT);
+            if deadline <= now {
+                self.abort();
+                return Ok(());
+            }
+        }
+
+        if self.timer.is_expired(now) {
+            if self.timer.is_exhausted() {
+                // the peer is gone, or the path to it
+                self.error = Some(io::ErrorKind::TimedOut);
+                self.abort();
+                return Ok(());
+            }
+            self.retransmit(nic)?;
+            self.timer.back_off(now);
+        }
+
+        // SACK loss recovery (RFC6675 S5): resend the holes that enough data
+        // above has been SACKed for, without waiting for the RTO
+        while let Some((start, end)) = self
+            .scoreboard
+            .next_lost(self.send.una, self.segment_size())
+        {
+            let at = start.wrapping_sub(self.send.una) as usize;
+            let payload: Vec<u8> = self
+                .unacked
+                .range(at..at + end.wrapping_sub(start) as usize)
+                .copied()
+                .collect();
+            self.write(nic, start, &payload)?;
+            self.scoreboard.retransmitted(self.send.una, end);
+        }
+
+        if let State::SynSent = self.state
+            && self.send.nxt == self.send.iss
+        {
+            // connect() has no access to the nic, so the SYN goes out here
+            self.tcph.syn = true;
+            self.write(nic, self.send.iss, &[])?;
+        }
+
+        // receiver-side silly window avoidance (RFC1122 S4.2.3.3): announce a
+        // window that reading has opened only once it has grown by a good chunk
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            let opened = self.recv_window().saturating_sub(self.recv.wnd) as usize;
+            if opened >= std::cmp::min(self.recv_buffer_size / 2, self.mss) {
+                self.send_ack(nic, &[])?;
+            }
+        }
+
+        if !matches!(self.state, State::Estab | State::CloseWait) {
+            // no new data can go out before the handshake or after our FIN
+            return Ok(());
+        }
+
+        let size = self.segment_size();
+        loop {
+            let nflight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+            let nunsent = self.unacked.len().saturating_sub(nflight);
+            let nallowed = (self.send.wnd as usize).saturating_sub(nflight);
+            let n = std::cmp::min(size, std::cmp::min(nunsent, nallowed));
+            if n == 0 {
+                break;
+            }
+
+            let payload: Vec<u8> = self.unacked.range(nflight..nflight + n).copied().collect();
+            self.write(nic, self.send.nxt, &payload)?;
+        }
+
+        // zero window probing (RFC9293 S3.8.6.1): with data waiting behind a
+        // closed window and nothing in flight, push one byte past the window
+        // once the persist timer runs out. Until the window opens, the peer
+        // drops it and the retransmission timer keeps resending it with backoff.
+        let nflight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        if self.send.wnd == 0 && nflight == 0 && !self.unacked.is_empty() {
+            match self.persist {
+                None => self.persist = Some(now + self.timer.rto),
+                Some(t) if t <= now => {
+                    self.persist = None;
+                    let probe = [self.unacked[0]];
+                    self.write(nic, self.send.nxt, &probe)?;
+                }
+                Some(_) => {}
+            }
+        } else {
+            self.persist = None;
+        }
+
+        let nflight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        if self.closed
+            && matches!(self.state, State::Estab | State::CloseWait)
+            && nflight == self.unacked.len()
+        {
+            // everything queued is on the wire, so the FIN can follow
+            self.send_fin(nic)?;
+        }
+        Ok(())
+    }
+
+    /// Shuts down reading, writing or both, like `std::net::TcpStream::shutdown`.
+    /// Shutting down writing sends a FIN once the queued data is out.
+    pub(crate) fn shutdown(&mut self, how: Shutdown) {
+        if let Shutdown::Write | Shutdown::Both = how {
+            self.closed = true;
+        }
+        if let Shutdown::Read | Shutdown::Both = how {
+            self.read_closed = true;
+            self.incoming.clear();
+        }
+    }
+
+    /// Aborts a connection that no application is going to see, e.g. because
+    /// its listener is gone before accepting it. The peer gets a RST on the
+    /// next tick rather than retransmitting into the void.
+    pub(crate) fn reset(&mut self) {
+        self.reset = true;
+        self.released = true;
+    }
+
+    /// Closes the connection on behalf of a dropped TcpStream.
+    pub(crate) fn release(&mut self) {
+        self.shutdown(Shutdown::Both);
+        self.released = true;
+    }
+
+    pub(crate) fn send_fin(&mut self, nic: &mut dyn NetDevice) -> io::Result<()> {
         self.tcph.fin = true;
         self.write(nic, self.send.nxt, &[])?;
         match self.state {
-            State::Estab => {
-                self.state = State::FinWait1;
-            }
+            State::Estab => self.state = State::FinWait1,
+            State::CloseWait => self.state = State::LastAck,
             _ => {}
         }
         Ok(())
     }
 }
 
+/// The largest payload to send to the peer of SYN `tcph`: what it announced in
+/// the MSS option, but no less than MIN_MSS, as long as it fits our own MTU.
+fn effective_mss(nic: &dyn NetDevice, tcph: &TcpHeaderSlice) -> usize {
+    let announced = tcph
+        .options_iterator()
+        .find_map(|option| match option {
+            Ok(TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss.into()),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_MSS);
+    std::cmp::min(
+        std::cmp::max(announced, MIN_MSS),
+        nic.mtu().saturating_sub(HEADERS_LEN),
+    )
+}
+
+/// TSval and TSecr of the timestamps option of `tcph`, if it has one.
+fn timestamps(tcph: &TcpHeaderSlice) -> Option<(u32, u32)> {
+    tcph.options_iterator().find_map(|option| match option {
+        Ok(TcpOptionElement::Timestamp(tsval, tsecr)) => Some((tsval, tsecr)),
+        _ => None,
+    })
+}
+
+/// The SACK blocks of `tcph`, if any.
+fn sack_blocks(tcph: &TcpHeaderSlice) -> Vec<(u32, u32)> {
+    tcph.options_iterator()
+        .find_map(|option| match option {
+            Ok(TcpOptionElement::SelectiveAcknowledgement(first, rest)) => Some(
+                std::iter::once(first)
+                    .chain(rest.into_iter().flatten())
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// Answers `tcph`, which came from `remote` to `local`, with a RST, e.g. when
+/// it belongs to no connection (RFC9293 S3.10.7.1). The peer then gives up
+/// right away instead of retrying.
+pub(crate) fn send_reset(
+    nic: &mut dyn NetDevice,
+    local: Ipv4Addr,
+    remote: Ipv4Addr,
+    tcph: &TcpHeaderSlice,
+    data: &[u8],
+) -> io::Result<()> {
+    if tcph.rst() {
+        // a RST is never answered, or two stacks could go on forever
+        return Ok(());
+    }
+    let mut rst = etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), 0, 0);
+    rst.rst = true;
+    if tcph.ack() {
+        // <SEQ=SEG.ACK><CTL=RST>
+        rst.sequence_number = tcph.acknowledgment_number();
+    } else {
+        // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+        let slen = data.len() as u32 + u32::from(tcph.syn()) + u32::from(tcph.fin());
+        rst.ack = true;
+        rst.acknowledgment_number = tcph.sequence_number().wrapping_add(slen);
+    }
+    let ip = etherparse::Ipv4Header::new(
+        rst.header_len() as u16,
+        64,
+        etherparse::IpNumber::TCP,
+        local.octets(),
+        remote.octets(),
+    )
+    .unwrap();
+    rst.checksum = rst.calc_checksum_ipv4(&ip, &[]).unwrap();
+
+    let mut buf = Vec::with_capacity(ip.header_len() + rst.header_len());
+    ip.write(&mut buf)?;
+    rst.write(&mut buf)?;
+    nic.send(&buf)?;
+    Ok(())
+}
+
 fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     // From RFC1323 S2.3:
     //   TCP determines if a data segment is "old" or "new" by testing
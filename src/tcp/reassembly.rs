@@ -0,0 +1,80 @@
+use super::wrapping_lt;
+use std::collections::VecDeque;
+
+/// Out-of-order data above RCV.NXT, waiting for the gap in front of it to fill.
+#[derive(Default)]
+pub(super) struct Reassembly {
+    // disjoint, non-adjacent ranges in sequence number order
+    segments: VecDeque<(u32, Vec<u8>)>,
+    // start of the range that took in the latest arrival
+    latest: u32,
+}
+
+impl Reassembly {
+    /// Holds `data` at `seq`, merging it with any range it overlaps or touches.
+    /// `nxt` is the current RCV.NXT, which every held range lies beyond.
+    pub(super) fn insert(&mut self, nxt: u32, seq: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        // offsets from RCV.NXT order the ranges even across a wrap
+        let start = seq.wrapping_sub(nxt) as usize;
+        let end = start + data.len();
+
+        let (mut lo, mut hi) = (start, end);
+        let mut merged = Vec::new();
+        let mut kept = VecDeque::with_capacity(self.segments.len() + 1);
+        for (s, d) in self.segments.drain(..) {
+            let off = s.wrapping_sub(nxt) as usize;
+            if off + d.len() < start || off > end {
+                kept.push_back((s, d));
+            } else {
+                lo = std::cmp::min(lo, off);
+                hi = std::cmp::max(hi, off + d.len());
+                merged.push((off, d));
+            }
+        }
+
+        let mut buf = vec![0u8; hi - lo];
+        for (off, d) in merged {
+            buf[off - lo..off - lo + d.len()].copy_from_slice(&d);
+        }
+        buf[start - lo..end - lo].copy_from_slice(data);
+
+        let at = kept
+            .iter()
+            .position(|(s, _)| s.wrapping_sub(nxt) as usize > lo)
+            .unwrap_or(kept.len());
+        self.latest = nxt.wrapping_add(lo as u32);
+        kept.insert(at, (self.latest, buf));
+        self.segments = kept;
+    }
+
+    /// The held ranges as SACK blocks, at most `max` of them. The one with the
+    /// latest arrival comes first, as RFC2018 S4 asks, the others follow in
+    /// sequence number order.
+    pub(super) fn sack_blocks(&self, max: usize) -> Vec<(u32, u32)> {
+        let block = |(s, d): &(u32, Vec<u8>)| (*s, s.wrapping_add(d.len() as u32));
+        let latest = self.segments.iter().filter(|(s, _)| *s == self.latest);
+        let others = self.segments.iter().filter(|(s, _)| *s != self.latest);
+        latest.chain(others).map(block).take(max).collect()
+    }
+
+    /// Moves the data that has become contiguous with `nxt` into `out` and
+    /// returns the new RCV.NXT.
+    pub(super) fn drain_into(&mut self, mut nxt: u32, out: &mut VecDeque<u8>) -> u32 {
+        while let Some((s, d)) = self.segments.front() {
+            if wrapping_lt(nxt, *s) {
+                // still a gap
+                break;
+            }
+            let unread_at = nxt.wrapping_sub(*s) as usize;
+            if unread_at < d.len() {
+                out.extend(&d[unread_at..]);
+                nxt = nxt.wrapping_add((d.len() - unread_at) as u32);
+            }
+            self.segments.pop_front();
+        }
+        nxt
+    }
+}
@@ -0,0 +1,108 @@
+// SACKed segments above a hole before it counts as lost (RFC6675 S2)
+const DUP_THRESH: usize = 3;
+
+/// What the peer has selectively acknowledged beyond SND.UNA, and how far the
+/// holes in between have been retransmitted (RFC6675 S3).
+#[derive(Default)]
+pub(super) struct Scoreboard {
+    // disjoint, non-adjacent ranges in sequence number order
+    blocks: Vec<(u32, u32)>,
+    // HighRxt: the end of the last hole retransmitted
+    high_rxt: u32,
+}
+
+impl Scoreboard {
+    /// Takes in the SACK `blocks` of an ACK, once SND.UNA has moved up to
+    /// `una`. Whatever lies outside of the data in flight up to `nxt` is
+    /// dropped, be it acked by now or bogus.
+    pub(super) fn update(&mut self, una: u32, nxt: u32, blocks: &[(u32, u32)]) {
+        // offsets from SND.UNA order the ranges even across a wrap
+        let flight = nxt.wrapping_sub(una);
+        let mut ranges: Vec<(u32, u32)> = self
+            .blocks
+            .iter()
+            .chain(blocks)
+            .filter_map(|&(s, e)| {
+                let (s, e) = (s.wrapping_sub(una), e.wrapping_sub(una));
+                // a block may start below SND.UNA and end above it
+                let s = if s > flight { 0 } else { s };
+                (s < e && e <= flight).then_some((s, e))
+            })
+            .collect();
+        ranges.sort_unstable();
+
+        self.blocks.clear();
+        let mut merged: Option<(u32, u32)> = None;
+        for (s, e) in ranges {
+            merged = match merged {
+                Some((ms, me)) if s <= me => Some((ms, std::cmp::max(me, e))),
+                Some((ms, me)) => {
+                    self.blocks
+                        .push((una.wrapping_add(ms), una.wrapping_add(me)));
+                    Some((s, e))
+                }
+                None => Some((s, e)),
+            };
+        }
+        if let Some((ms, me)) = merged {
+            self.blocks
+                .push((una.wrapping_add(ms), una.wrapping_add(me)));
+        }
+
+        if self.high_rxt.wrapping_sub(una) > flight {
+            // the retransmitted holes are all acked
+            self.high_rxt = una;
+        }
+    }
+
+    /// Forgets all SACKs, for when the peer turns out to have dropped data
+    /// it had SACKed (RFC2018 S8).
+    pub(super) fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// Where the hole starting at `seq` ends: at the next SACKed block, or at
+    /// `end` if there is none in between.
+    pub(super) fn hole_end(&self, una: u32, seq: u32, end: u32) -> u32 {
+        let off = seq.wrapping_sub(una);
+        self.blocks
+            .iter()
+            .map(|&(s, _)| s)
+            .find(|s| s.wrapping_sub(una) >= off)
+            .filter(|s| s.wrapping_sub(una) < end.wrapping_sub(una))
+            .unwrap_or(end)
+    }
+
+    /// The next hole to retransmit, at most `mss` bytes of it (NextSeg rule 1
+    /// of RFC6675 S4): the first unSACKed range beyond HighRxt that enough
+    /// data has been SACKed above to deem it lost. None if `mss` leaves no
+    /// room for data, as an empty hole would never move HighRxt on.
+    pub(super) fn next_lost(&self, una: u32, mss: usize) -> Option<(u32, u32)> {
+        if mss == 0 {
+            return None;
+        }
+        let mut start = self.high_rxt.wrapping_sub(una);
+        for (i, &(s, e)) in self.blocks.iter().enumerate() {
+            let (s, e) = (s.wrapping_sub(una), e.wrapping_sub(una));
+            if start < s {
+                // IsLost(): holes further up have even less SACKed above them
+                let above = &self.blocks[i..];
+                let sacked: u32 = above.iter().map(|&(s, e)| e.wrapping_sub(s)).sum();
+                if above.len() < DUP_THRESH && (sacked as usize) <= (DUP_THRESH - 1) * mss {
+                    return None;
+                }
+                let end = std::cmp::min(s, start + mss as u32);
+                return Some((una.wrapping_add(start), una.wrapping_add(end)));
+            }
+            start = std::cmp::max(start, e);
+        }
+        None
+    }
+
+    /// Notes that the hole up to `end` has been retransmitted.
+    pub(super) fn retransmitted(&mut self, una: u32, end: u32) {
+        if self.high_rxt.wrapping_sub(una) < end.wrapping_sub(una) {
+            self.high_rxt = end;
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! What the integration tests share: the addresses, simulated setups, a raw
+//! peer that speaks TCP by hand, and devices wrapping another one.
+
+// every test crate builds its own copy and uses only some of it
+#![allow(dead_code)]
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tcprs::{Interface, NetDevice, Pipe, TcpStream, VirtualClock};
+
+pub const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+pub const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+// where the server listens
+pub const PORT: u16 = 7;
+// where the raw peer sends from
+pub const PEER_PORT: u16 = 40000;
+
+/// Lets `ifaces` exchange packets for `n` steps of 10ms.
+pub fn run(ifaces: &mut [&mut Interface], clock: &VirtualClock, n: usize) {
+    for _ in 0..n {
+        for iface in ifaces.iter_mut() {
+            iface.poll().unwrap();
+        }
+        clock.advance(Duration::from_millis(10));
+    }
+}
+
+/// A server on `a` and a client on `b`, simulated with a shared clock.
+pub fn simulated(
+    a: impl NetDevice + 'static,
+    b: impl NetDevice + 'static,
+) -> (Interface, Interface, VirtualClock) {
+    let clock = VirtualClock::new();
+    let server = Interface::simulated(a, SERVER, clock.clone());
+    let client = Interface::simulated(b, CLIENT, clock.clone());
+    (server, client, clock)
+}
+
+/// Connects `client` to `server` on PORT and returns the client's stream
+/// and the accepted one.
+pub fn connect(
+    server: &mut Interface,
+    client: &mut Interface,
+    clock: &VirtualClock,
+) -> (TcpStream, TcpStream) {
+    let mut l = server.bind(PORT).unwrap();
+    let stream = client.connect(SocketAddrV4::new(SERVER, PORT)).unwrap();
+    run(&mut [server, client], clock, 2);
+    (stream, l.accept().unwrap())
+}
+
+/// Sends `len` bytes from a client on `b` to an echo server on `a`, both
+/// running their own packet loop, and checks that they come back. The send
+/// buffers hold just 1 KiB, so that writes beyond that have to block.
+pub fn echo_over(a: impl NetDevice + 'static, b: impl NetDevice + 'static, len: u32) {
+    let mut server = Interface::with_device(a, SERVER);
+    let mut client = Interface::with_device(b, CLIENT);
+    server.set_send_buffer_size(1024);
+    client.set_send_buffer_size(1024);
+
+    let mut l = server.bind(PORT).unwrap();
+    let jh = thread::spawn(move || {
+        let mut stream = l.accept().unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        stream.write_all(&data).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, PORT)).unwrap();
+    let sent: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    stream.write_all(&sent).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, sent);
+    jh.join().unwrap();
+}
+
+pub type TcpBuilder = etherparse::PacketBuilderStep<etherparse::TcpHeader>;
+
+/// A segment from CLIENT:PEER_PORT to SERVER:PORT.
+pub fn segment(seq: u32, build: impl FnOnce(TcpBuilder) -> TcpBuilder) -> Vec<u8> {
+    let mut packet = Vec::new();
+    build(
+        etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+            .tcp(PEER_PORT, PORT, seq, 8192),
+    )
+    .write(&mut packet, &[])
+    .unwrap();
+    packet
+}
+
+/// Sends `segment(seq, build)` through `peer`.
+pub fn send(peer: &mut Pipe, seq: u32, build: impl FnOnce(TcpBuilder) -> TcpBuilder) {
+    peer.send(&segment(seq, build)).unwrap();
+}
+
+/// The TCP header of the next segment `peer` receives within `timeout`.
+pub fn recv(peer: &mut Pipe, timeout: Duration) -> Option<etherparse::TcpHeader> {
+    let mut buf = [0u8; 1504];
+    let n = peer.recv(&mut buf, timeout).unwrap()?;
+    let (_, rest) = etherparse::Ipv4Header::from_slice(&buf[..n]).unwrap();
+    let (tcph, _) = etherparse::TcpHeader::from_slice(rest).unwrap();
+    Some(tcph)
+}
+
+/// Opens a connection from the raw `peer` to PORT on `server`, with 1000 as
+/// the peer's ISN, and returns the server's ISS. The connection is then ready
+/// to be accepted.
+pub fn accept_raw(server: &mut Interface, peer: &mut Pipe) -> u32 {
+    send(peer, 1000, |b| b.syn());
+    server.poll().unwrap();
+    let synack = recv(peer, Duration::from_secs(5)).unwrap();
+    assert!(synack.syn && synack.ack);
+    let iss = synack.sequence_number;
+    send(peer, 1001, |b| b.ack(iss.wrapping_add(1)));
+    server.poll().unwrap();
+    iss
+}
+
+/// A device on a link with a smaller MTU.
+pub struct WithMtu<D>(pub D, pub usize);
+
+impl<D: NetDevice> NetDevice for WithMtu<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        self.0.recv(buf, timeout)
+    }
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        assert!(buf.len() <= self.mtu());
+        self.0.send(buf)
+    }
+    fn mtu(&self) -> usize {
+        self.1
+    }
+}
+
+/// Passes datagrams through and checks the ones sent with etherparse.
+pub struct Checked<D>(pub D);
+
+impl<D: NetDevice> NetDevice for Checked<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        self.0.recv(buf, timeout)
+    }
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (iph, rest) = etherparse::Ipv4Header::from_slice(buf).unwrap();
+        assert_eq!(iph.header_checksum, iph.calc_header_checksum());
+        assert_eq!(usize::from(iph.total_len), buf.len());
+        let (tcph, payload) = etherparse::TcpHeader::from_slice(rest).unwrap();
+        assert_eq!(
+            tcph.checksum,
+            tcph.calc_checksum_ipv4(&iph, payload).unwrap(),
+            "bad checksum of {tcph:?} with {} bytes of payload",
+            payload.len()
+        );
+        self.0.send(buf)
+    }
+    fn mtu(&self) -> usize {
+        self.0.mtu()
+    }
+}
+
+/// Passes datagrams through and keeps a copy of the ones sent.
+pub struct Recorded<D>(pub D, pub Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl<D: NetDevice> NetDevice for Recorded<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        self.0.recv(buf, timeout)
+    }
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.1.lock().unwrap().push(buf.to_vec());
+        self.0.send(buf)
+    }
+    fn mtu(&self) -> usize {
+        self.0.mtu()
+    }
+}
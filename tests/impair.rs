@@ -0,0 +1,74 @@
+mod common;
+
+use common::echo_over;
+use std::time::Duration;
+use tcprs::{Impaired, Impairments};
+
+/// Sends a few segments' worth of data from client to server and back over
+/// links impaired by `imp`, each direction with its own seed.
+fn echo_impaired(imp: Impairments) {
+    let (a, b) = tcprs::pipe();
+    let a = Impaired::new(a, imp.clone());
+    let b = Impaired::new(
+        b,
+        Impairments {
+            seed: imp.seed + 1,
+            ..imp
+        },
+    );
+    echo_over(a, b, 4000);
+}
+
+#[test]
+fn echo_with_loss() {
+    echo_impaired(Impairments {
+        seed: 1,
+        loss: 0.1,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn echo_with_reordering_and_duplicates() {
+    echo_impaired(Impairments {
+        seed: 7,
+        latency: Duration::from_millis(2),
+        jitter: Duration::from_millis(5),
+        reorder: 0.3,
+        reorder_delay: Duration::from_millis(20),
+        duplicate: 0.2,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn echo_with_corruption() {
+    echo_impaired(Impairments {
+        seed: 5,
+        corrupt: 0.1,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn echo_with_bandwidth_cap() {
+    echo_impaired(Impairments {
+        seed: 3,
+        latency: Duration::from_millis(5),
+        bandwidth: Some(200_000),
+        ..Default::default()
+    });
+}
+
+#[test]
+#[should_panic(expected = "a link needs some bandwidth")]
+fn zero_bandwidth_is_rejected() {
+    let (a, _b) = tcprs::pipe();
+    Impaired::new(
+        a,
+        Impairments {
+            bandwidth: Some(0),
+            ..Default::default()
+        },
+    );
+}
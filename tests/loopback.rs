@@ -0,0 +1,123 @@
+mod common;
+
+use common::{CLIENT, PORT, SERVER, accept_raw, echo_over, send};
+use std::io::{self, Read, Write};
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn echo_over_pipe() {
+    let (a, b) = tcprs::pipe();
+    // more than fits into the send queue and a single segment
+    echo_over(a, b, 10_000);
+}
+
+#[test]
+fn listener_keeps_accepting() {
+    let (a, b) = tcprs::pipe();
+    let mut server = tcprs::Interface::with_device(a, SERVER);
+    let mut client = tcprs::Interface::with_device(b, CLIENT);
+
+    let mut l = server.bind(8000).unwrap();
+    let jh = thread::spawn(move || {
+        for i in 0..3u8 {
+            let mut stream = l.accept().unwrap();
+            stream.write_all(&[i]).unwrap();
+            stream.flush().unwrap();
+        }
+    });
+
+    for i in 0..3u8 {
+        let mut stream = client.connect(SocketAddrV4::new(SERVER, 8000)).unwrap();
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], i);
+    }
+    jh.join().unwrap();
+}
+
+#[test]
+fn reset_wakes_blocked_reader() {
+    let (a, mut peer) = tcprs::pipe();
+    let mut server = tcprs::Interface::with_device(a, SERVER);
+    let mut l = server.bind(PORT).unwrap();
+
+    accept_raw(&mut server, &mut peer);
+
+    let mut stream = l.accept().unwrap();
+    let jh = thread::spawn(move || stream.read(&mut [0u8; 16]).unwrap_err().kind());
+    // give the reader time to block
+    thread::sleep(Duration::from_millis(100));
+    send(&mut peer, 1001, |b| b.rst());
+    assert_eq!(jh.join().unwrap(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn connect_to_unbound_port_is_refused() {
+    let (a, b) = tcprs::pipe();
+    let _server = tcprs::Interface::with_device(a, SERVER);
+    let mut client = tcprs::Interface::with_device(b, CLIENT);
+
+    let e = client.connect(SocketAddrV4::new(SERVER, 9)).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+/// Passes datagrams through until the link goes away, which it does once
+/// the flag is set.
+struct Breakable<D>(D, Arc<AtomicBool>);
+
+impl<D: tcprs::NetDevice> tcprs::NetDevice for Breakable<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<Option<usize>> {
+        if self.1.load(Ordering::SeqCst) {
+            return Err(io::Error::other("link is down"));
+        }
+        self.0.recv(buf, timeout)
+    }
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.1.load(Ordering::SeqCst) {
+            return Err(io::Error::other("link is down"));
+        }
+        self.0.send(buf)
+    }
+}
+
+#[test]
+fn failed_packet_loop_does_not_panic_on_drop() {
+    let (a, _b) = tcprs::pipe();
+    let server =
+        tcprs::Interface::with_device(Breakable(a, Arc::new(AtomicBool::new(true))), SERVER);
+    // give the packet loop time to fail
+    thread::sleep(Duration::from_millis(100));
+    drop(server);
+}
+
+#[test]
+fn failed_packet_loop_wakes_blocked_calls() {
+    let (a, b) = tcprs::pipe();
+    let broken = Arc::new(AtomicBool::new(false));
+    let mut server = tcprs::Interface::with_device(Breakable(a, broken.clone()), SERVER);
+    let mut client = tcprs::Interface::with_device(b, CLIENT);
+
+    let mut l = server.bind(PORT).unwrap();
+    let _stream = client.connect(SocketAddrV4::new(SERVER, PORT)).unwrap();
+    let mut accepted = l.accept().unwrap();
+    let jh = thread::spawn(move || {
+        let read = accepted.read(&mut [0u8; 16]).err();
+        (read, accepted.write(&[1]).err())
+    });
+    let jh_accept = thread::spawn(move || l.accept().err());
+    // give the reader and the listener time to block
+    thread::sleep(Duration::from_millis(100));
+    broken.store(true, Ordering::SeqCst);
+
+    let (read, write) = jh.join().unwrap();
+    assert_eq!(read.unwrap().kind(), io::ErrorKind::Other);
+    assert_eq!(write.unwrap().kind(), io::ErrorKind::Other);
+    assert_eq!(
+        jh_accept.join().unwrap().unwrap().kind(),
+        io::ErrorKind::Other
+    );
+}
@@ -0,0 +1,484 @@
+//! Runs the packetdrill-like scripts in tests/scripts against a simulated
+//! interface. Every line of a script is a point in time followed by an event:
+//!
+//! ```text
+//! # the peer sends a segment
+//! 0.100 < S 1000:1000(0) win 8192
+//! # the stack has to send this segment within TOLERANCE of the given time
+//! +0 > S. 0:0(0) ack 1001 win 65535
+//! # a socket call and what it is expected to return
+//! +0.1 accept
+//! ```
+//!
+//! Times are in seconds, either absolute or, with a leading `+`, relative to
+//! the previous line. Segments are written like tcpdump does: the flags `S`,
+//! `F`, `R`, `P` and `.` for ACK, then the sequence number, the end of the
+//! payload and its length, and last the options in angle brackets, e.g.
+//! `<mss 1460,sackOK,TS val 100 ecr 0>` or `<nop,nop,sack 1001:2001>`.
+//! The peer's sequence numbers are taken as they are, ours are relative to
+//! our ISS, in SACK blocks too. Our timestamps count milliseconds from the
+//! creation of the connection. Any segment the stack sends that the script
+//! doesn't expect fails the run.
+//!
+//! Socket calls are `bind PORT`, `connect PORT`, `accept`, `write N` (which
+//! has to take all N bytes), `read N` (which has to return N bytes, 0 being
+//! EOF), `shutdown rd|wr|rdwr` and `close`. In place of the result, the name
+//! of an `io::ErrorKind` expects the call to fail with it, e.g. `read
+//! WouldBlock`. Finally, `isn N` fixes our initial sequence numbers and
+//! `rcvbuf N` sets the receive buffer size.
+
+use etherparse::TcpOptionElement;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::time::Duration;
+use tcprs::{Interface, NetDevice, Pipe, TcpListener, TcpStream, VirtualClock};
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const REMOTE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+// port of the peer when the stack is the one listening
+const REMOTE_PORT: u16 = 40000;
+// port the peer sends to if the script neither binds nor connects
+const LOCAL_PORT: u16 = 8080;
+// the stack is polled in steps of this long, so timers fire up to this late
+const STEP: Duration = Duration::from_millis(10);
+const TOLERANCE: Duration = STEP;
+
+macro_rules! scripts {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                let path = concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/scripts/",
+                    stringify!($name),
+                    ".pkt"
+                );
+                run(path, &std::fs::read_to_string(path).unwrap());
+            }
+        )*
+    };
+}
+
+scripts!(
+    ack_unsent,
+    active_open,
+    active_open_dup_synack,
+    isn_wraparound,
+    listen_non_syn,
+    mss,
+    mss_default,
+    mss_floor,
+    passive_open,
+    rst_established,
+    rst_in_syn_rcvd,
+    rst_in_syn_sent,
+    sack_option_space,
+    sack_receive,
+    sack_recovery,
+    sack_recovery_full_mss,
+    sack_tiny_mss,
+    shutdown_both,
+    shutdown_read,
+    simultaneous_close,
+    simultaneous_open,
+    syn_established,
+    syn_rcvd_bad_ack,
+    syn_sent_bad_ack,
+    timestamps,
+    timestamps_rtt,
+    unbound_port,
+    window_edges,
+    window_scale,
+    window_scale_refused,
+    window_update,
+    zero_window_probe,
+);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Segment {
+    syn: bool,
+    fin: bool,
+    rst: bool,
+    psh: bool,
+    ack: bool,
+    seq: u32,
+    len: u32,
+    ack_number: u32,
+    // unchecked if not given
+    window: Option<u16>,
+    options: Option<Vec<TcpOptionElement>>,
+}
+
+impl Segment {
+    fn parse(words: &[&str]) -> Result<Self, String> {
+        let mut s = Segment::default();
+        // the options come last, in angle brackets
+        let words = match words.iter().position(|w| w.starts_with('<')) {
+            Some(i) => {
+                s.options = Some(parse_options(&words[i..].join(" "))?);
+                &words[..i]
+            }
+            None => words,
+        };
+        let mut words = words.iter();
+        for flag in words.next().ok_or("missing flags")?.chars() {
+            match flag {
+                'S' => s.syn = true,
+                'F' => s.fin = true,
+                'R' => s.rst = true,
+                'P' => s.psh = true,
+                '.' => s.ack = true,
+                _ => return Err(format!("unknown flag {flag:?}")),
+            }
+        }
+
+        // seq:end(len)
+        let range = words.next().ok_or("missing sequence numbers")?;
+        let (seq, rest) = range.split_once(':').ok_or("missing ':'")?;
+        let (end, len) = rest.split_once('(').ok_or("missing '('")?;
+        let len = len.strip_suffix(')').ok_or("missing ')'")?;
+        s.seq = number(seq)?;
+        s.len = number(len)?;
+        if number::<u32>(end)? != s.seq.wrapping_add(s.len) {
+            return Err(format!("{range} doesn't add up"));
+        }
+
+        while let Some(word) = words.next() {
+            let value = words.next().ok_or(format!("missing value of {word}"))?;
+            match *word {
+                "ack" => s.ack_number = number(value)?,
+                "win" => s.window = Some(number(value)?),
+                _ => return Err(format!("unknown field {word}")),
+            }
+        }
+        Ok(s)
+    }
+
+    fn from_packet(packet: &[u8]) -> Self {
+        let iph = etherparse::Ipv4HeaderSlice::from_slice(packet).unwrap();
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).unwrap();
+        let payload = &packet[iph.slice().len() + tcph.slice().len()..];
+        assert_eq!(
+            tcph.calc_checksum_ipv4(&iph, payload).unwrap(),
+            tcph.checksum(),
+            "segment sent with a bad checksum"
+        );
+        let len = payload.len();
+        Segment {
+            syn: tcph.syn(),
+            fin: tcph.fin(),
+            rst: tcph.rst(),
+            psh: tcph.psh(),
+            ack: tcph.ack(),
+            seq: tcph.sequence_number(),
+            len: len as u32,
+            ack_number: if tcph.ack() {
+                tcph.acknowledgment_number()
+            } else {
+                0
+            },
+            window: Some(tcph.window_size()),
+            options: Some(tcph.options_iterator().map(|o| o.unwrap()).collect()),
+        }
+    }
+
+    /// Whether `sent` is what this expected segment describes.
+    fn matches(&self, sent: &Segment) -> bool {
+        let mut sent = sent.clone();
+        if self.window.is_none() {
+            sent.window = None;
+        }
+        if self.options.is_none() {
+            sent.options = None;
+        }
+        sent == *self
+    }
+}
+
+/// Parses options like `<mss 1460,nop>`; `<>` stands for none at all.
+fn parse_options(s: &str) -> Result<Vec<TcpOptionElement>, String> {
+    let s = s
+        .strip_prefix('<')
+        .and_then(|s| s.strip_suffix('>'))
+        .ok_or(format!("{s} is not in angle brackets"))?;
+    let mut options = Vec::new();
+    for option in s.split(',').filter(|o| !o.trim().is_empty()) {
+        let words: Vec<&str> = option.split_whitespace().collect();
+        options.push(match words[..] {
+            ["nop"] => TcpOptionElement::Noop,
+            ["mss", mss] => TcpOptionElement::MaximumSegmentSize(number(mss)?),
+            ["wscale", shift] => TcpOptionElement::WindowScale(number(shift)?),
+            ["sackOK"] => TcpOptionElement::SelectiveAcknowledgementPermitted,
+            ["sack", ref blocks @ ..] if (1..=4).contains(&blocks.len()) => {
+                let mut blocks = blocks.iter().map(|b| {
+                    let (start, end) = b.split_once(':').ok_or(format!("bad SACK block {b}"))?;
+                    Ok::<_, String>((number(start)?, number(end)?))
+                });
+                let first = blocks.next().unwrap()?;
+                let mut rest = [None; 3];
+                for (r, block) in rest.iter_mut().zip(blocks) {
+                    *r = Some(block?);
+                }
+                TcpOptionElement::SelectiveAcknowledgement(first, rest)
+            }
+            ["TS", "val", tsval, "ecr", tsecr] => {
+                TcpOptionElement::Timestamp(number(tsval)?, number(tsecr)?)
+            }
+            _ => return Err(format!("unknown option {option}")),
+        });
+    }
+    Ok(options)
+}
+
+fn number<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("{s:?} is not a number"))
+}
+
+/// Parses seconds with up to nanosecond precision, without going through f64.
+fn seconds(s: &str) -> Result<Duration, String> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    if frac.len() > 9 {
+        return Err(format!("{s:?} is too precise"));
+    }
+    let nanos = format!("{frac:0<9}");
+    Ok(Duration::new(number(secs)?, number(&nanos)?))
+}
+
+/// Checks the outcome of a socket call against the expected number of bytes
+/// or `io::ErrorKind`.
+fn expect(expected: &str, result: io::Result<usize>) -> Result<(), String> {
+    let actual = match &result {
+        Ok(n) => n.to_string(),
+        Err(e) => format!("{:?}", e.kind()),
+    };
+    if actual != expected {
+        return Err(format!("expected {expected}, got {result:?}"));
+    }
+    Ok(())
+}
+
+struct Runner {
+    clock: VirtualClock,
+    iface: Interface,
+    peer: Pipe,
+    // time since the start of the script
+    elapsed: Duration,
+    // segments the stack has sent and the script has yet to expect
+    sent: Vec<(Duration, Segment)>,
+    iss: u32,
+    local_port: u16,
+    remote_port: u16,
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+}
+
+impl Runner {
+    fn new() -> Self {
+        let (a, peer) = tcprs::pipe();
+        let clock = VirtualClock::new();
+        Runner {
+            iface: Interface::simulated(a, LOCAL, clock.clone()),
+            clock,
+            peer,
+            elapsed: Duration::ZERO,
+            sent: Vec::new(),
+            iss: 0,
+            local_port: LOCAL_PORT,
+            remote_port: REMOTE_PORT,
+            listener: None,
+            stream: None,
+        }
+    }
+
+    /// Runs the stack and collects what it sends.
+    fn poll(&mut self) {
+        self.iface.poll().unwrap();
+        let mut buf = [0u8; 1504];
+        while let Some(n) = self.peer.recv(&mut buf, Duration::ZERO).unwrap() {
+            let mut s = Segment::from_packet(&buf[..n]);
+            if s.syn {
+                self.iss = s.seq;
+            }
+            if self.local_port == 0 {
+                // the ephemeral port connect picked
+                let iph = etherparse::Ipv4HeaderSlice::from_slice(&buf[..n]).unwrap();
+                let tcph =
+                    etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..n]).unwrap();
+                self.local_port = tcph.source_port();
+            }
+            s.seq = s.seq.wrapping_sub(self.iss);
+            self.sent.push((self.elapsed, s));
+        }
+    }
+
+    fn advance_to(&mut self, t: Duration) {
+        while self.elapsed < t {
+            let step = std::cmp::min(STEP, t - self.elapsed);
+            self.clock.advance(step);
+            self.elapsed += step;
+            self.poll();
+        }
+    }
+
+    fn inject(&mut self, s: &Segment) -> Result<(), String> {
+        if let Some((t, s)) = self.sent.first() {
+            return Err(format!("unexpected segment at {t:?}: {s:?}"));
+        }
+        let mut tcph = etherparse::TcpHeader::new(
+            self.remote_port,
+            self.local_port,
+            s.seq,
+            s.window.unwrap_or(u16::MAX),
+        );
+        tcph.syn = s.syn;
+        tcph.fin = s.fin;
+        tcph.rst = s.rst;
+        tcph.psh = s.psh;
+        tcph.ack = s.ack;
+        tcph.acknowledgment_number = s.ack_number.wrapping_add(self.iss);
+        // SACK blocks refer to our sequence numbers, just like the ack number
+        let relative =
+            |(start, end): (u32, u32)| (start.wrapping_add(self.iss), end.wrapping_add(self.iss));
+        let options: Vec<TcpOptionElement> = s
+            .options
+            .iter()
+            .flatten()
+            .map(|option| match *option {
+                TcpOptionElement::SelectiveAcknowledgement(first, rest) => {
+                    TcpOptionElement::SelectiveAcknowledgement(
+                        relative(first),
+                        rest.map(|block| block.map(relative)),
+                    )
+                }
+                ref option => option.clone(),
+            })
+            .collect();
+        tcph.set_options(&options).map_err(|e| e.to_string())?;
+        let payload: Vec<u8> = (0..s.len).map(|i| i as u8).collect();
+        let mut packet = Vec::new();
+        etherparse::PacketBuilder::ipv4(REMOTE.octets(), LOCAL.octets(), 64)
+            .tcp_header(tcph)
+            .write(&mut packet, &payload)
+            .unwrap();
+        self.peer.send(&packet).unwrap();
+        Ok(())
+    }
+
+    fn expect_sent(&mut self, at: Duration, s: &Segment) -> Result<(), String> {
+        if self.sent.is_empty() {
+            return Err(format!("expected {s:?}, but nothing was sent"));
+        }
+        let (t, sent) = self.sent.remove(0);
+        if !s.matches(&sent) {
+            return Err(format!("expected {s:?}, but {sent:?} was sent"));
+        }
+        if t + TOLERANCE < at {
+            return Err(format!("sent too early, at {t:?}"));
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, words: &[&str]) -> Result<(), String> {
+        let arg = words.get(1).copied();
+        match words[0] {
+            "rcvbuf" => {
+                let size = number(arg.ok_or("missing size")?)?;
+                self.iface.set_recv_buffer_size(size);
+            }
+            "isn" => {
+                let isn: u32 = number(arg.ok_or("missing number")?)?;
+                self.iface.set_isn_generator(move |_, _, _| isn);
+            }
+            "bind" => {
+                let port = number(arg.ok_or("missing port")?)?;
+                self.listener = Some(self.iface.bind(port).map_err(|e| e.to_string())?);
+                self.local_port = port;
+            }
+            "connect" => {
+                let port = number(arg.ok_or("missing port")?)?;
+                let stream = self
+                    .iface
+                    .connect(SocketAddrV4::new(REMOTE, port))
+                    .map_err(|e| e.to_string())?;
+                self.stream = Some(stream);
+                self.remote_port = port;
+                self.local_port = 0;
+            }
+            "accept" => {
+                let result = self.listener.as_mut().ok_or("not bound")?.accept();
+                let result = result.map(|stream| {
+                    self.stream = Some(stream);
+                    0
+                });
+                // accepting a connection is the default
+                expect(arg.unwrap_or("0"), result)?;
+            }
+            "write" => {
+                let stream = self.stream.as_mut().ok_or("no connection")?;
+                let arg = arg.ok_or("missing length")?;
+                let n = arg.parse().unwrap_or(1);
+                expect(arg, stream.write(&vec![0u8; n]))?;
+            }
+            "read" => {
+                let stream = self.stream.as_mut().ok_or("no connection")?;
+                let arg = arg.ok_or("missing length")?;
+                let n = arg.parse().unwrap_or(0);
+                // one byte more, to notice if there is more to read than expected
+                expect(arg, stream.read(&mut vec![0u8; n + 1]))?;
+            }
+            "shutdown" => {
+                let how = match arg {
+                    Some("rd") => Shutdown::Read,
+                    Some("wr") => Shutdown::Write,
+                    Some("rdwr") => Shutdown::Both,
+                    _ => return Err("expected rd, wr or rdwr".into()),
+                };
+                let stream = self.stream.as_ref().ok_or("no connection")?;
+                stream.shutdown(how).map_err(|e| e.to_string())?;
+            }
+            "close" => {
+                self.stream.take().ok_or("no connection")?;
+            }
+            call => return Err(format!("unknown call {call}")),
+        }
+        self.poll();
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let at = match words[0].strip_prefix('+') {
+            Some(rel) => self.elapsed + seconds(rel)?,
+            None => seconds(words[0])?,
+        };
+        if at < self.elapsed {
+            return Err("time goes backwards".into());
+        }
+        self.advance_to(at);
+
+        match *words.get(1).ok_or("missing event")? {
+            "<" => self.inject(&Segment::parse(&words[2..])?)?,
+            ">" => self.expect_sent(at, &Segment::parse(&words[2..])?)?,
+            _ => return self.call(&words[1..]),
+        }
+        self.poll();
+        Ok(())
+    }
+}
+
+fn run(path: &str, script: &str) {
+    let mut r = Runner::new();
+    for (i, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Err(e) = r.line(line) {
+            panic!("{path}:{}: {line}\n{e}", i + 1);
+        }
+    }
+    if let Some((t, s)) = r.sent.first() {
+        panic!("{path}: unexpected segment at {t:?}: {s:?}");
+    }
+}
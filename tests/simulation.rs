@@ -0,0 +1,430 @@
+mod common;
+
+use common::{
+    CLIENT, PORT, Recorded, SERVER, WithMtu, accept_raw, connect, recv, run, segment, send,
+    simulated,
+};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tcprs::{Interface, NetDevice, VirtualClock};
+
+#[test]
+fn syn_is_retransmitted_with_backoff() {
+    let (a, mut peer) = tcprs::pipe();
+    let clock = VirtualClock::new();
+    let mut client = Interface::simulated(a, CLIENT, clock.clone());
+    let _stream = client.connect(SocketAddrV4::new(SERVER, PORT)).unwrap();
+
+    let mut buf = [0u8; 1504];
+    let mut sent = || {
+        client.poll().unwrap();
+        peer.recv(&mut buf, Duration::ZERO).unwrap().is_some()
+    };
+    assert!(sent());
+    // the initial RTO is one second and doubles with every retransmission
+    for rto in [1000, 2000, 4000] {
+        clock.advance(Duration::from_millis(rto - 1));
+        assert!(!sent());
+        clock.advance(Duration::from_millis(1));
+        assert!(sent());
+    }
+}
+
+#[test]
+fn connect_times_out() {
+    let (a, mut peer) = tcprs::pipe();
+    let clock = VirtualClock::new();
+    let mut client = Interface::simulated(a, CLIENT, clock.clone());
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, PORT)).unwrap();
+
+    // the SYN and eight retransmissions, the last ones a minute apart
+    let mut buf = [0u8; 1504];
+    let mut syns = 0;
+    for _ in 0..300 {
+        client.poll().unwrap();
+        while peer.recv(&mut buf, Duration::ZERO).unwrap().is_some() {
+            syns += 1;
+        }
+        clock.advance(Duration::from_secs(1));
+    }
+    assert_eq!(syns, 9);
+    let err = stream.read(&mut buf).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn unanswered_syn_ack_is_given_up() {
+    let (a, mut peer) = tcprs::pipe();
+    let clock = VirtualClock::new();
+    let mut server = Interface::simulated(a, SERVER, clock.clone());
+    let mut l = server.bind(PORT).unwrap();
+    let mut buf = [0u8; 1504];
+
+    peer.send(&syn()).unwrap();
+    for _ in 0..300 {
+        server.poll().unwrap();
+        while peer.recv(&mut buf, Duration::ZERO).unwrap().is_some() {}
+        clock.advance(Duration::from_secs(1));
+    }
+    // nothing is left to accept, and the same SYN starts over
+    assert_eq!(l.accept().err().unwrap().kind(), ErrorKind::WouldBlock);
+    peer.send(&syn()).unwrap();
+    server.poll().unwrap();
+    let tcph = recv(&mut peer, Duration::ZERO).unwrap();
+    assert!(tcph.syn && tcph.ack);
+    assert!(l.accept().is_ok());
+}
+
+#[test]
+fn echo_step_by_step() {
+    let (a, b) = tcprs::pipe();
+    let (mut server, mut client, clock) = simulated(a, b);
+
+    let mut l = server.bind(PORT).unwrap();
+    assert_eq!(l.accept().err().unwrap().kind(), ErrorKind::WouldBlock);
+    let mut stream = client.connect(SocketAddrV4::new(SERVER, PORT)).unwrap();
+    run(&mut [&mut server, &mut client], &clock, 2);
+    let mut accepted = l.accept().unwrap();
+
+    let mut buf = [0u8; 16];
+    assert_eq!(
+        accepted.read(&mut buf).err().unwrap().kind(),
+        ErrorKind::WouldBlock
+    );
+    stream.write_all(b"hello").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    run(&mut [&mut server, &mut client], &clock, 2);
+    assert_eq!(accepted.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(accepted.read(&mut buf).unwrap(), 0);
+
+    accepted.write_all(&buf[..5]).unwrap();
+    drop(accepted);
+    run(&mut [&mut server, &mut client], &clock, 2);
+    assert_eq!(stream.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+}
+
+/// A SYN to the server from the raw peer.
+fn syn() -> Vec<u8> {
+    segment(1000, |b| b.syn())
+}
+
+#[test]
+fn corrupted_segments_are_dropped() {
+    let (a, mut peer) = tcprs::pipe();
+    let mut server = Interface::simulated(a, SERVER, VirtualClock::new());
+    let _l = server.bind(PORT).unwrap();
+    let mut buf = [0u8; 1504];
+
+    // flip a bit of the sequence number, which follows the IPv4 header and
+    // the ports
+    let mut bad = syn();
+    bad[24] ^= 1;
+    peer.send(&bad).unwrap();
+    server.poll().unwrap();
+    assert!(peer.recv(&mut buf, Duration::ZERO).unwrap().is_none());
+    assert_eq!(server.stats().checksum_errors, 1);
+
+    peer.send(&syn()).unwrap();
+    server.poll().unwrap();
+    assert!(peer.recv(&mut buf, Duration::ZERO).unwrap().is_some());
+    assert_eq!(server.stats().checksum_errors, 1);
+}
+
+#[test]
+fn checksums_are_not_verified_with_offloading() {
+    let (a, mut peer) = tcprs::pipe();
+    let mut server = Interface::simulated(a, SERVER, VirtualClock::new());
+    server.set_verify_checksums(false);
+    let _l = server.bind(PORT).unwrap();
+
+    // the device is trusted to have checked the TCP checksum
+    let mut bad = syn();
+    bad[36] ^= 0xff;
+    peer.send(&bad).unwrap();
+    server.poll().unwrap();
+    let mut buf = [0u8; 1504];
+    assert!(peer.recv(&mut buf, Duration::ZERO).unwrap().is_some());
+    assert_eq!(server.stats().checksum_errors, 0);
+}
+
+#[test]
+fn bad_total_length_is_dropped() {
+    let (a, mut peer) = tcprs::pipe();
+    let mut server = Interface::simulated(a, SERVER, VirtualClock::new());
+    let _l = server.bind(PORT).unwrap();
+    let mut buf = [0u8; 1504];
+
+    // shorter than the IPv4 header, then longer than the packet
+    for total_len in [10u16, 100] {
+        let mut bad = syn();
+        bad[2..4].copy_from_slice(&total_len.to_be_bytes());
+        peer.send(&bad).unwrap();
+        server.poll().unwrap();
+        assert!(peer.recv(&mut buf, Duration::ZERO).unwrap().is_none());
+    }
+}
+
+#[test]
+fn padding_is_ignored() {
+    let (a, mut peer) = tcprs::pipe();
+    let mut server = Interface::simulated(a, SERVER, VirtualClock::new());
+    let _l = server.bind(PORT).unwrap();
+
+    // e.g. what an Ethernet frame of the minimum size carries
+    let mut padded = syn();
+    padded.extend([0; 6]);
+    peer.send(&padded).unwrap();
+    server.poll().unwrap();
+    let mut buf = [0u8; 1504];
+    assert!(peer.recv(&mut buf, Duration::ZERO).unwrap().is_some());
+    assert_eq!(server.stats().checksum_errors, 0);
+}
+
+#[test]
+fn time_wait_ends_after_two_msl() {
+    let (a, mut peer) = tcprs::pipe();
+    let clock = VirtualClock::new();
+    let mut server = Interface::simulated(a, SERVER, clock.clone());
+    let mut l = server.bind(PORT).unwrap();
+
+    let iss = accept_raw(&mut server, &mut peer);
+
+    // an active close: our FIN, the peer's ACK of it and its own FIN
+    drop(l.accept().unwrap());
+    server.poll().unwrap();
+    assert!(recv(&mut peer, Duration::ZERO).unwrap().fin);
+    send(&mut peer, 1001, |b| b.ack(iss.wrapping_add(2)).fin());
+    server.poll().unwrap();
+    assert!(recv(&mut peer, Duration::ZERO).unwrap().ack);
+
+    // TIME-WAIT still holds the quad, so a new SYN doesn't open a connection
+    send(&mut peer, 5000, |b| b.syn());
+    server.poll().unwrap();
+    assert!(!recv(&mut peer, Duration::ZERO).unwrap().syn);
+
+    // 2MSL later, the connection is gone and the quad is free again
+    for _ in 0..61 {
+        clock.advance(Duration::from_secs(1));
+        server.poll().unwrap();
+    }
+    send(&mut peer, 5000, |b| b.syn());
+    server.poll().unwrap();
+    let synack = recv(&mut peer, Duration::ZERO).unwrap();
+    assert!(synack.syn && synack.ack);
+    assert!(l.accept().is_ok());
+}
+
+/// The sequence number of the SYN that connecting to `port` sends.
+fn isn_of_connect(client: &mut Interface, peer: &mut tcprs::Pipe, port: u16) -> u32 {
+    let _stream = client.connect(SocketAddrV4::new(SERVER, port)).unwrap();
+    client.poll().unwrap();
+    let tcph = recv(peer, Duration::ZERO).unwrap();
+    assert!(tcph.syn);
+    tcph.sequence_number
+}
+
+#[test]
+fn isns_depend_on_the_quad() {
+    let (a, mut peer) = tcprs::pipe();
+    let mut client = Interface::simulated(a, CLIENT, VirtualClock::new());
+    let isn1 = isn_of_connect(&mut client, &mut peer, 7);
+    let isn2 = isn_of_connect(&mut client, &mut peer, 8);
+    assert_ne!(isn1, isn2);
+}
+
+#[test]
+fn isn_generator_can_be_replaced() {
+    let (a, mut peer) = tcprs::pipe();
+    let mut client = Interface::simulated(a, CLIENT, VirtualClock::new());
+    client.set_isn_generator(|_, remote: SocketAddrV4, _| u32::from(remote.port()) * 1000);
+    assert_eq!(isn_of_connect(&mut client, &mut peer, 7), 7000);
+    assert_eq!(isn_of_connect(&mut client, &mut peer, 8), 8000);
+}
+
+#[test]
+fn mss_follows_the_mtu() {
+    let (a, b) = tcprs::pipe();
+    let (mut server, mut client, clock) = simulated(a, WithMtu(b, 576));
+    let (mut stream, mut accepted) = connect(&mut server, &mut client, &clock);
+
+    // the server has to cut its segments to fit the client's MTU
+    let sent: Vec<u8> = (0..1024u32).map(|i| i as u8).collect();
+    accepted.write_all(&sent).unwrap();
+    accepted.shutdown(Shutdown::Write).unwrap();
+    run(&mut [&mut server, &mut client], &clock, 2);
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn orphaned_fin_wait_2_times_out() {
+    let (a, mut peer) = tcprs::pipe();
+    let clock = VirtualClock::new();
+    let mut server = Interface::simulated(a, SERVER, clock.clone());
+    let mut l = server.bind(PORT).unwrap();
+
+    let iss = accept_raw(&mut server, &mut peer);
+
+    // the peer acks our FIN, but never sends its own
+    drop(l.accept().unwrap());
+    server.poll().unwrap();
+    assert!(recv(&mut peer, Duration::ZERO).unwrap().fin);
+    send(&mut peer, 1001, |b| b.ack(iss.wrapping_add(2)));
+    server.poll().unwrap();
+
+    // FIN-WAIT-2 still holds the quad, so a new SYN doesn't open a connection
+    clock.advance(Duration::from_secs(59));
+    server.poll().unwrap();
+    send(&mut peer, 5000, |b| b.syn());
+    server.poll().unwrap();
+    assert!(!recv(&mut peer, Duration::ZERO).unwrap().syn);
+
+    // a minute later, the connection is gone and the quad is free again
+    clock.advance(Duration::from_secs(1));
+    server.poll().unwrap();
+    send(&mut peer, 5000, |b| b.syn());
+    server.poll().unwrap();
+    let synack = recv(&mut peer, Duration::ZERO).unwrap();
+    assert!(synack.syn && synack.ack);
+    assert!(l.accept().is_ok());
+}
+
+#[test]
+fn full_send_queue_drains_on_acks() {
+    let (a, b) = tcprs::pipe();
+    let (mut server, mut client, clock) = simulated(a, b);
+    client.set_send_buffer_size(100);
+    let (mut stream, mut accepted) = connect(&mut server, &mut client, &clock);
+
+    // a write takes what fits, then the next one would block, as would flush
+    assert_eq!(stream.write(&[1; 150]).unwrap(), 100);
+    assert_eq!(
+        stream.write(&[1; 50]).err().unwrap().kind(),
+        ErrorKind::WouldBlock
+    );
+    assert_eq!(stream.flush().err().unwrap().kind(), ErrorKind::WouldBlock);
+
+    // once the server has acked it, there is room again
+    run(&mut [&mut server, &mut client], &clock, 2);
+    stream.flush().unwrap();
+    assert_eq!(stream.write(&[2; 150]).unwrap(), 100);
+    run(&mut [&mut server, &mut client], &clock, 2);
+
+    let mut buf = [0u8; 256];
+    assert_eq!(accepted.read(&mut buf).unwrap(), 200);
+    assert_eq!(buf[..200], [[1; 100], [2; 100]].concat());
+}
+
+/// What the client sends when four connections write a byte each.
+fn trace_of_four_connections() -> Vec<Vec<u8>> {
+    let (a, b) = tcprs::pipe();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let (mut server, mut client, clock) = simulated(a, Recorded(b, sent.clone()));
+    server.set_isn_generator(|_, _, _| 1000);
+    client.set_isn_generator(|_, _, _| 5000);
+
+    let mut l = server.bind(PORT).unwrap();
+    let mut streams: Vec<_> = (0..4)
+        .map(|_| client.connect(SocketAddrV4::new(SERVER, PORT)).unwrap())
+        .collect();
+    run(&mut [&mut server, &mut client], &clock, 2);
+    for stream in &mut streams {
+        stream.write_all(&[1]).unwrap();
+    }
+    run(&mut [&mut server, &mut client], &clock, 2);
+    for _ in 0..4 {
+        l.accept().unwrap();
+    }
+    sent.lock().unwrap().clone()
+}
+
+#[test]
+fn simulation_is_deterministic() {
+    // the connections tick in the same order, so the segments go out in it
+    let first = trace_of_four_connections();
+    for _ in 0..4 {
+        assert_eq!(trace_of_four_connections(), first);
+    }
+}
+
+/// Fails to send the segments to the port in the mutex, if any.
+struct FailingTo<D>(D, Arc<Mutex<Option<u16>>>);
+
+impl<D: NetDevice> NetDevice for FailingTo<D> {
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<Option<usize>> {
+        self.0.recv(buf, timeout)
+    }
+    fn send(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (_, rest) = etherparse::Ipv4Header::from_slice(buf).unwrap();
+        let (tcph, _) = etherparse::TcpHeader::from_slice(rest).unwrap();
+        if *self.1.lock().unwrap() == Some(tcph.destination_port) {
+            return Err(std::io::Error::other("no route"));
+        }
+        self.0.send(buf)
+    }
+}
+
+#[test]
+fn send_error_fails_only_its_connection() {
+    let (a, b) = tcprs::pipe();
+    let failing = Arc::new(Mutex::new(None));
+    let (mut server, mut client, clock) = simulated(FailingTo(a, failing.clone()), b);
+    let (_stream1, mut accepted1) = connect(&mut server, &mut client, &clock);
+    let mut l = server.bind(PORT + 1).unwrap();
+    let mut stream2 = client.connect(SocketAddrV4::new(SERVER, PORT + 1)).unwrap();
+    run(&mut [&mut server, &mut client], &clock, 2);
+    let mut accepted2 = l.accept().unwrap();
+
+    // connect took the first ephemeral port
+    *failing.lock().unwrap() = Some(49152);
+    accepted1.write_all(b"lost").unwrap();
+    accepted2.write_all(b"hello").unwrap();
+    run(&mut [&mut server, &mut client], &clock, 2);
+
+    assert_eq!(
+        accepted1.read(&mut [0u8; 16]).err().unwrap().kind(),
+        ErrorKind::Other
+    );
+    let mut buf = [0u8; 16];
+    assert_eq!(stream2.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test]
+fn dropped_listener_resets_unaccepted_connections() {
+    let (a, mut peer) = tcprs::pipe();
+    let mut server = Interface::simulated(a, SERVER, VirtualClock::new());
+
+    // established, but never accepted
+    let l = server.bind(PORT).unwrap();
+    let iss = accept_raw(&mut server, &mut peer);
+    drop(l);
+    server.poll().unwrap();
+    let rst = recv(&mut peer, Duration::ZERO).unwrap();
+    assert!(rst.rst);
+    assert_eq!(rst.sequence_number, iss.wrapping_add(1));
+
+    // half-open, in SYN-RECEIVED
+    let l = server.bind(PORT).unwrap();
+    send(&mut peer, 1000, |b| b.syn());
+    server.poll().unwrap();
+    let iss = recv(&mut peer, Duration::ZERO).unwrap().sequence_number;
+    drop(l);
+    server.poll().unwrap();
+    let rst = recv(&mut peer, Duration::ZERO).unwrap();
+    assert!(rst.rst);
+    assert_eq!(rst.sequence_number, iss.wrapping_add(1));
+
+    // and both are gone
+    server.poll().unwrap();
+    assert!(recv(&mut peer, Duration::ZERO).is_none());
+    send(&mut peer, 1001, |b| b.ack(iss.wrapping_add(1)));
+    server.poll().unwrap();
+    assert!(recv(&mut peer, Duration::ZERO).unwrap().rst);
+}